@@ -0,0 +1,242 @@
+//! Minimal stand-in for a Shelly Gen2 device, to try the `shelly_gen2` actor
+//! without real hardware:
+//!
+//!     cargo run --example mock_shelly_rpc -- 127.0.0.1:8080 [--password <password>]
+//!
+//! and configure `actor = { type = "shelly_gen2", host = "127.0.0.1:8080" }`.
+//! RPC frames are accepted over HTTP and websocket at `/rpc`. With a password
+//! every request needs SHA-256 digest credentials of the `admin` user and is
+//! answered with a 401 challenge otherwise.
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+/// Output and pending `toggle_after` deadline per switch id
+type Switches = Arc<Mutex<HashMap<u64, (bool, Option<Instant>)>>>;
+
+const REALM: &str = "mock";
+const NONCE: u64 = 1234;
+
+fn handle_rpc(switches: &Switches, request: &Value) -> Value {
+    let id = request["params"]["id"].as_u64().unwrap_or_default();
+    let mut switches = switches.lock().unwrap();
+    let switch = switches.entry(id).or_insert((false, None));
+    if switch.1.is_some_and(|deadline| Instant::now() >= deadline) {
+        *switch = (!switch.0, None);
+    }
+    match request["method"].as_str() {
+        Some("Switch.Set") => {
+            let was_on = switch.0;
+            switch.0 = request["params"]["on"].as_bool().unwrap_or(was_on);
+            switch.1 = request["params"]["toggle_after"]
+                .as_f64()
+                .map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+            println!("switch {id}: {} ({:?})", switch.0, switch.1);
+            json!({"id": request["id"], "result": {"was_on": was_on}})
+        }
+        Some("Switch.GetStatus") => {
+            json!({"id": request["id"], "result": {"id": id, "output": switch.0}})
+        }
+        _ => json!({"id": request["id"], "error": {"code": 404, "message": "No handler"}}),
+    }
+}
+
+fn sha256_hex(input: &str) -> String {
+    Sha256::digest(input.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Checks the digest `response` of the `admin` user for the given `ha2`.
+fn verify(password: &str, nc: &str, cnonce: &str, ha2: &str, response: &str) -> bool {
+    let ha1 = sha256_hex(&format!("admin:{REALM}:{password}"));
+    sha256_hex(&format!("{ha1}:{NONCE}:{nc}:{cnonce}:auth:{ha2}")) == response
+}
+
+/// Checks the value of an HTTP `Authorization: Digest ...` header.
+fn verify_header(password: &str, header: &str) -> bool {
+    let Some(params) = header.trim().strip_prefix("Digest") else {
+        return false;
+    };
+    let params: HashMap<_, _> = params
+        .split(',')
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+        .collect();
+    let param = |key| params.get(key).copied().unwrap_or_default();
+    param("username") == "admin"
+        && param("realm") == REALM
+        && param("nonce") == NONCE.to_string()
+        && param("uri") == "/rpc"
+        && param("qop") == "auth"
+        && verify(
+            password,
+            param("nc"),
+            param("cnonce"),
+            &sha256_hex("POST:/rpc"),
+            param("response"),
+        )
+}
+
+/// Checks the `auth` object of an RPC frame. The device doesn't know method
+/// and uri of frames, so these are fixed.
+fn verify_frame(password: &str, request: &Value) -> bool {
+    let auth = &request["auth"];
+    let cnonce = match &auth["cnonce"] {
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        _ => return false,
+    };
+    auth["username"] == "admin"
+        && auth["realm"] == REALM
+        && auth["nonce"] == NONCE
+        && auth["response"].as_str().is_some_and(|response| {
+            verify(
+                password,
+                "1",
+                &cnonce,
+                &sha256_hex("dummy_method:dummy_uri"),
+                response,
+            )
+        })
+}
+
+/// Error frame asking for authentication, as sent over the websocket.
+fn challenge_frame(request: &Value) -> Value {
+    let challenge = json!({
+        "auth_type": "digest",
+        "nonce": NONCE,
+        "nc": 1,
+        "realm": REALM,
+        "algorithm": "SHA-256",
+    });
+    json!({"id": request["id"], "error": {"code": 401, "message": challenge.to_string()}})
+}
+
+async fn serve_ws(
+    stream: TcpStream,
+    switches: Switches,
+    password: Option<String>,
+) -> std::io::Result<()> {
+    let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    while let Some(message) = ws.next().await {
+        let Ok(Message::Text(text)) = message else {
+            continue;
+        };
+        let request: Value = serde_json::from_str(&text).unwrap_or_default();
+        let response = match &password {
+            Some(password) if !verify_frame(password, &request) => challenge_frame(&request),
+            _ => handle_rpc(&switches, &request),
+        };
+        if ws.send(Message::text(response.to_string())).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn serve(
+    stream: TcpStream,
+    switches: Switches,
+    password: Option<String>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut content_length = 0;
+    let mut authorized = password.is_none();
+    let mut ws_key = None;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.trim().parse().unwrap_or_default(),
+            "authorization" => {
+                authorized |= password
+                    .as_ref()
+                    .is_some_and(|password| verify_header(password, value));
+            }
+            "sec-websocket-key" => ws_key = Some(value.trim().to_string()),
+            _ => (),
+        }
+    }
+
+    if let Some(key) = ws_key {
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            derive_accept_key(key.as_bytes())
+        );
+        reader.get_mut().write_all(response.as_bytes()).await?;
+        // the client doesn't send frames before the handshake completed, so
+        // nothing is left in the reader's buffer
+        return serve_ws(reader.into_inner(), switches, password).await;
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    let response = if !authorized {
+        format!(
+            "HTTP/1.1 401 Unauthorized\r\n\
+             WWW-Authenticate: Digest qop=\"auth\", realm=\"{REALM}\", nonce=\"{NONCE}\", algorithm=SHA-256\r\n\
+             Content-Length: 0\r\n\r\n"
+        )
+    } else {
+        let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let body = handle_rpc(&switches, &request).to_string();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    };
+    reader.get_mut().write_all(response.as_bytes()).await
+}
+
+/// Answers the connections of `listener` until accepting one fails.
+pub async fn run(listener: TcpListener, password: Option<String>) -> std::io::Result<()> {
+    let switches = Switches::default();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let switches = switches.clone();
+        let password = password.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, switches, password).await {
+                eprintln!("{e}");
+            }
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let addr = args
+        .get(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .cloned()
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let password = args
+        .iter()
+        .position(|arg| arg == "--password")
+        .and_then(|i| args.get(i + 1))
+        .cloned();
+    let listener = TcpListener::bind(&addr).await?;
+    println!("Mock Shelly listening on {addr}");
+    run(listener, password).await
+}
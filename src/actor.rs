@@ -0,0 +1,709 @@
+use anyhow::{Context, anyhow, bail};
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::config::{ActorBackendConfig, RpcTransport};
+
+/// A relay that switches a heating circuit.
+pub trait Actor: Send + Sync {
+    /// Short description for log messages
+    fn describe(&self) -> String;
+
+    fn set(&self, on: bool) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Switches on and back off after `duration`. Backends that support it let
+    /// the relay do this on its own, so it also switches off if we crash.
+    fn set_on_for(&self, duration: Duration) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Reads back whether the relay is currently on.
+    fn state(&self) -> BoxFuture<'_, anyhow::Result<bool>>;
+
+    /// Whether `state` can read back the relay at all. Commands to relays
+    /// that can't are trusted once they were sent.
+    fn can_read_back(&self) -> bool {
+        true
+    }
+}
+
+pub fn create_actor(config: &ActorBackendConfig) -> Arc<dyn Actor> {
+    match config {
+        ActorBackendConfig::ShellyGen1 { url } => Arc::new(ShellyGen1 {
+            url: url.clone(),
+            client: http_client(),
+        }),
+        ActorBackendConfig::ShellyGen2 {
+            host,
+            channel,
+            transport,
+            password,
+        } => Arc::new(ShellyGen2 {
+            host: host.clone(),
+            channel: *channel,
+            transport: *transport,
+            password: password.clone(),
+            client: http_client(),
+            ws: tokio::sync::Mutex::new(None),
+            next_id: AtomicU64::new(1),
+        }),
+        ActorBackendConfig::Gpio { pin, active_low } => Arc::new(Gpio {
+            pin: *pin,
+            active_low: *active_low,
+            off_timer: OffTimer::default(),
+        }),
+        ActorBackendConfig::Mqtt {
+            host,
+            port,
+            username,
+            password,
+            command_topic,
+            state_topic,
+            payload_on,
+            payload_off,
+        } => {
+            // the broker drops the older of two connections with the same id
+            let mut options = MqttOptions::new(
+                format!("homectl-actor-{}-{command_topic}", std::process::id()),
+                host,
+                *port,
+            );
+            options.set_keep_alive(Duration::from_secs(30));
+            if let (Some(username), Some(password)) = (username, password) {
+                options.set_credentials(username, password);
+            }
+            Arc::new(Mqtt {
+                options,
+                command_topic: command_topic.clone(),
+                state_topic: state_topic.clone(),
+                payload_on: payload_on.clone(),
+                payload_off: payload_off.clone(),
+                connection: OnceCell::new(),
+                off_timer: OffTimer::default(),
+            })
+        }
+    }
+}
+
+fn http_client() -> reqwest::Client {
+    reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+}
+
+/// Switches an actor off after a delay, for backends without a relay timer.
+#[derive(Default)]
+struct OffTimer(Mutex<Option<JoinHandle<()>>>);
+
+impl OffTimer {
+    /// Cancels a pending switch off.
+    fn cancel(&self) {
+        if let Some(handle) = self.0.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    fn start(&self, delay: Duration, off: BoxFuture<'static, anyhow::Result<()>>) {
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = off.await {
+                eprintln!("Timed switch off failed: {e:#}");
+            }
+        });
+        if let Some(old) = self.0.lock().unwrap().replace(handle) {
+            old.abort();
+        }
+    }
+}
+
+/// Shelly Gen1 style relay (`/relay/N?turn=on&timer=`).
+pub struct ShellyGen1 {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl ShellyGen1 {
+    async fn request(&self, query: &[(&str, &str)]) -> anyhow::Result<serde_json::Value> {
+        let request = self.client.get(&self.url).query(query);
+        let response = request.send().await?.error_for_status()?;
+        let status = serde_json::from_str(&response.text().await?)?;
+        Ok(status)
+    }
+}
+
+fn ison(status: &serde_json::Value) -> anyhow::Result<bool> {
+    status["ison"]
+        .as_bool()
+        .context("relay status without 'ison'")
+}
+
+impl Actor for ShellyGen1 {
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn set(&self, on: bool) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            let turn = if on { "on" } else { "off" };
+            self.request(&[("turn", turn)]).await?;
+            Ok(())
+        })
+    }
+
+    fn set_on_for(&self, duration: Duration) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            let timer = duration.as_secs().to_string();
+            self.request(&[("turn", "on"), ("timer", &timer)]).await?;
+            Ok(())
+        })
+    }
+
+    fn state(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async move { ison(&self.request(&[]).await?) })
+    }
+}
+
+fn sha256_hex(input: &str) -> String {
+    Sha256::digest(input.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Client nonce for digest authentication
+fn cnonce() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:x}", nanos ^ std::process::id() as u128)
+}
+
+/// Shelly Gen2+ devices authenticate as `admin` with SHA-256 digest auth.
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    /// Nonce count, as it goes into the response
+    nc: String,
+}
+
+impl DigestChallenge {
+    /// Parses a `WWW-Authenticate: Digest realm="..", nonce=".."` header.
+    fn from_header(header: &str) -> Option<Self> {
+        let params = header.trim().strip_prefix("Digest")?;
+        let mut realm = None;
+        let mut nonce = None;
+        for param in params.split(',') {
+            let Some((key, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim() {
+                "realm" => realm = Some(value),
+                "nonce" => nonce = Some(value),
+                _ => (),
+            }
+        }
+        Some(Self {
+            realm: realm?,
+            nonce: nonce?,
+            nc: "00000001".to_string(),
+        })
+    }
+
+    /// Parses the JSON challenge in the message of an RPC 401 error.
+    fn from_rpc_error(message: &str) -> Option<Self> {
+        let challenge: Value = serde_json::from_str(message).ok()?;
+        let nonce = match &challenge["nonce"] {
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => s.clone(),
+            _ => return None,
+        };
+        Some(Self {
+            realm: challenge["realm"].as_str()?.to_string(),
+            nonce,
+            nc: challenge["nc"].as_u64().unwrap_or(1).to_string(),
+        })
+    }
+
+    fn response(&self, password: &str, cnonce: &str, ha2: &str) -> String {
+        let ha1 = sha256_hex(&format!("admin:{}:{password}", self.realm));
+        sha256_hex(&format!(
+            "{ha1}:{}:{}:{cnonce}:auth:{ha2}",
+            self.nonce, self.nc
+        ))
+    }
+
+    /// Value of the `Authorization` header for a request to `/rpc`.
+    fn authorization(&self, password: &str) -> String {
+        let cnonce = cnonce();
+        let response = self.response(password, &cnonce, &sha256_hex("POST:/rpc"));
+        format!(
+            "Digest username=\"admin\", realm=\"{}\", nonce=\"{}\", uri=\"/rpc\", \
+             algorithm=SHA-256, response=\"{response}\", qop=auth, nc={}, cnonce=\"{cnonce}\"",
+            self.realm, self.nonce, self.nc
+        )
+    }
+
+    /// `auth` object for RPC frames sent over the websocket.
+    fn auth_object(&self, password: &str) -> Value {
+        let cnonce = cnonce();
+        // the device doesn't check method and uri of websocket requests
+        let response = self.response(password, &cnonce, &sha256_hex("dummy_method:dummy_uri"));
+        json!({
+            "realm": self.realm,
+            "username": "admin",
+            "nonce": self.nonce.parse::<u64>().map(Value::from).unwrap_or(Value::from(self.nonce.clone())),
+            "cnonce": cnonce,
+            "response": response,
+            "algorithm": "SHA-256",
+        })
+    }
+}
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Time to wait for the websocket to connect and for each message
+const WS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shelly Gen2/Gen3 switch, controlled through JSON-RPC (`Switch.Set`,
+/// `Switch.GetStatus`) over HTTP or a websocket.
+pub struct ShellyGen2 {
+    /// Host name, optionally with port
+    host: String,
+    /// Switch id
+    channel: u32,
+    transport: RpcTransport,
+    password: Option<String>,
+    client: reqwest::Client,
+    ws: tokio::sync::Mutex<Option<WsStream>>,
+    next_id: AtomicU64,
+}
+
+/// Extracts the result of an RPC response frame, or its error.
+fn rpc_result(mut frame: Value) -> Result<Value, (i64, String)> {
+    if let Some(error) = frame.get("error") {
+        let code = error["code"].as_i64().unwrap_or_default();
+        let message = error["message"].as_str().unwrap_or_default().to_string();
+        return Err((code, message));
+    }
+    Ok(frame["result"].take())
+}
+
+impl ShellyGen2 {
+    async fn call(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut request = json!({
+            "id": id,
+            "src": "homectl",
+            "method": method,
+            "params": params,
+        });
+        match self.transport {
+            RpcTransport::Http => self.call_http(&request).await,
+            RpcTransport::Websocket => {
+                let mut ws = self.ws.lock().await;
+                let result = self.call_ws(&mut ws, &mut request).await;
+                if result.is_err() {
+                    // start with a fresh connection next time
+                    *ws = None;
+                }
+                result
+            }
+        }
+    }
+
+    async fn call_http(&self, request: &Value) -> anyhow::Result<Value> {
+        let url = format!("http://{}/rpc", self.host);
+        let send = |authorization: Option<String>| {
+            let mut builder = self
+                .client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(request.to_string());
+            if let Some(authorization) = authorization {
+                builder = builder.header(reqwest::header::AUTHORIZATION, authorization);
+            }
+            builder.send()
+        };
+
+        let mut response = send(None).await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            let password = self
+                .password
+                .as_ref()
+                .context("device requires authentication, but no password configured")?;
+            let challenge = response
+                .headers()
+                .get(reqwest::header::WWW_AUTHENTICATE)
+                .and_then(|h| h.to_str().ok())
+                .and_then(DigestChallenge::from_header)
+                .context("unsupported authentication challenge")?;
+            response = send(Some(challenge.authorization(password))).await?;
+        }
+        let response = response.error_for_status()?;
+        let frame: Value = serde_json::from_str(&response.text().await?)?;
+        rpc_result(frame).map_err(|(code, message)| anyhow!("RPC error {code}: {message}"))
+    }
+
+    async fn call_ws(
+        &self,
+        ws: &mut Option<WsStream>,
+        request: &mut Value,
+    ) -> anyhow::Result<Value> {
+        let stream = match ws {
+            Some(stream) => stream,
+            None => {
+                let url = format!("ws://{}/rpc", self.host);
+                let connect = tokio_tungstenite::connect_async(&url);
+                let (stream, _) = tokio::time::timeout(WS_TIMEOUT, connect)
+                    .await
+                    .with_context(|| format!("Timeout connecting to {url}"))?
+                    .with_context(|| format!("Failed to connect to {url}"))?;
+                ws.insert(stream)
+            }
+        };
+
+        let mut authenticated = false;
+        loop {
+            stream.send(Message::text(request.to_string())).await?;
+            let frame = loop {
+                // on errors including timeouts, `call` drops the connection
+                let message = tokio::time::timeout(WS_TIMEOUT, stream.next())
+                    .await
+                    .context("Timeout waiting for the response")?
+                    .context("websocket closed")??;
+                let Message::Text(text) = message else {
+                    continue;
+                };
+                let frame: Value = serde_json::from_str(&text)?;
+                // skip notifications and stale responses
+                if frame["id"] == request["id"] {
+                    break frame;
+                }
+            };
+            match rpc_result(frame) {
+                Ok(result) => return Ok(result),
+                Err((401, message)) if !authenticated => {
+                    let password = self
+                        .password
+                        .as_ref()
+                        .context("device requires authentication, but no password configured")?;
+                    let challenge = DigestChallenge::from_rpc_error(&message)
+                        .context("unsupported authentication challenge")?;
+                    request["auth"] = challenge.auth_object(password);
+                    authenticated = true;
+                }
+                Err((code, message)) => bail!("RPC error {code}: {message}"),
+            }
+        }
+    }
+}
+
+impl Actor for ShellyGen2 {
+    fn describe(&self) -> String {
+        format!("{} switch {}", self.host, self.channel)
+    }
+
+    fn set(&self, on: bool) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.call("Switch.Set", json!({"id": self.channel, "on": on}))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn set_on_for(&self, duration: Duration) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            let params = json!({
+                "id": self.channel,
+                "on": true,
+                "toggle_after": duration.as_secs_f64(),
+            });
+            self.call("Switch.Set", params).await?;
+            Ok(())
+        })
+    }
+
+    fn state(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let status = self
+                .call("Switch.GetStatus", json!({"id": self.channel}))
+                .await?;
+            status["output"]
+                .as_bool()
+                .context("switch status without 'output'")
+        })
+    }
+}
+
+/// Relay on a GPIO pin, exported through `/sys/class/gpio`.
+pub struct Gpio {
+    pin: u32,
+    active_low: bool,
+    off_timer: OffTimer,
+}
+
+impl Gpio {
+    fn path(&self, file: &str) -> String {
+        format!("/sys/class/gpio/gpio{}/{file}", self.pin)
+    }
+
+    fn write(path: String, value: bool, active_low: bool) -> anyhow::Result<()> {
+        let level = if value != active_low { "1" } else { "0" };
+        std::fs::write(&path, level).with_context(|| format!("Failed to write {path}"))
+    }
+
+    fn export(&self) -> anyhow::Result<()> {
+        if std::fs::metadata(self.path("value")).is_err() {
+            std::fs::write("/sys/class/gpio/export", self.pin.to_string())
+                .with_context(|| format!("Failed to export gpio {}", self.pin))?;
+        }
+        // writing "out" would drive the pin low, "low"/"high" also set the
+        // initial level, which is kept at off
+        let direction = std::fs::read_to_string(self.path("direction")).unwrap_or_default();
+        if direction.trim() == "out" {
+            return Ok(());
+        }
+        let off = if self.active_low { "high" } else { "low" };
+        std::fs::write(self.path("direction"), off)
+            .with_context(|| format!("Failed to configure gpio {}", self.pin))
+    }
+}
+
+impl Actor for Gpio {
+    fn describe(&self) -> String {
+        format!("gpio {}", self.pin)
+    }
+
+    fn set(&self, on: bool) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.off_timer.cancel();
+            self.export()?;
+            Self::write(self.path("value"), on, self.active_low)
+        })
+    }
+
+    fn set_on_for(&self, duration: Duration) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.set(true).await?;
+            let (path, active_low) = (self.path("value"), self.active_low);
+            let off = async move { Self::write(path, false, active_low) };
+            self.off_timer.start(duration, Box::pin(off));
+            Ok(())
+        })
+    }
+
+    fn state(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let path = self.path("value");
+            let value =
+                std::fs::read_to_string(&path).with_context(|| format!("Failed to read {path}"))?;
+            Ok((value.trim() == "1") != self.active_low)
+        })
+    }
+}
+
+/// Relay controlled through MQTT, e.g. Tasmota or zigbee2mqtt.
+pub struct Mqtt {
+    options: MqttOptions,
+    command_topic: String,
+    state_topic: Option<String>,
+    payload_on: String,
+    payload_off: String,
+    /// Client and last state reported on `state_topic`, connected on first use
+    connection: OnceCell<(AsyncClient, Arc<Mutex<Option<bool>>>)>,
+    off_timer: OffTimer,
+}
+
+impl Mqtt {
+    async fn connection(&self) -> &(AsyncClient, Arc<Mutex<Option<bool>>>) {
+        self.connection
+            .get_or_init(|| async {
+                let (client, mut eventloop) = AsyncClient::new(self.options.clone(), 10);
+                let state = Arc::new(Mutex::new(None));
+                let state_clone = state.clone();
+                let client_clone = client.clone();
+                let state_topic = self.state_topic.clone();
+                let payload_on = self.payload_on.clone();
+                let payload_off = self.payload_off.clone();
+                tokio::spawn(async move {
+                    loop {
+                        match eventloop.poll().await {
+                            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                                if let Some(topic) = &state_topic {
+                                    let _ = client_clone.try_subscribe(topic, QoS::AtLeastOnce);
+                                }
+                            }
+                            Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                                let payload = String::from_utf8_lossy(&publish.payload);
+                                let on = if payload == payload_on {
+                                    Some(true)
+                                } else if payload == payload_off {
+                                    Some(false)
+                                } else {
+                                    None
+                                };
+                                *state_clone.lock().unwrap() = on;
+                            }
+                            Ok(_) => (),
+                            Err(e) => {
+                                eprintln!("MQTT actor: {e}");
+                                *state_clone.lock().unwrap() = None;
+                                tokio::time::sleep(Duration::from_secs(5)).await;
+                            }
+                        }
+                    }
+                });
+                (client, state)
+            })
+            .await
+    }
+
+    async fn publish(client: AsyncClient, topic: String, payload: String) -> anyhow::Result<()> {
+        client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .context("MQTT publish failed")
+    }
+}
+
+impl Actor for Mqtt {
+    fn describe(&self) -> String {
+        format!("mqtt {}", self.command_topic)
+    }
+
+    fn set(&self, on: bool) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.off_timer.cancel();
+            let (client, _) = self.connection().await;
+            let payload = if on {
+                &self.payload_on
+            } else {
+                &self.payload_off
+            };
+            Self::publish(client.clone(), self.command_topic.clone(), payload.clone()).await
+        })
+    }
+
+    fn set_on_for(&self, duration: Duration) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.set(true).await?;
+            let (client, _) = self.connection().await;
+            let off = Self::publish(
+                client.clone(),
+                self.command_topic.clone(),
+                self.payload_off.clone(),
+            );
+            self.off_timer.start(duration, Box::pin(off));
+            Ok(())
+        })
+    }
+
+    fn state(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async move {
+            if self.state_topic.is_none() {
+                bail!("no state topic configured");
+            }
+            let (_, state) = self.connection().await;
+            let state = *state.lock().unwrap();
+            state.context("no state received yet")
+        })
+    }
+
+    fn can_read_back(&self) -> bool {
+        self.state_topic.is_some()
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../examples/mock_shelly_rpc.rs"]
+mod mock_shelly_rpc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Starts the mock device on an ephemeral port and returns its address.
+    async fn mock_shelly(password: Option<&str>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(mock_shelly_rpc::run(listener, password.map(str::to_string)));
+        host
+    }
+
+    fn shelly(host: String, transport: RpcTransport, password: Option<&str>) -> Arc<dyn Actor> {
+        create_actor(&ActorBackendConfig::ShellyGen2 {
+            host,
+            channel: 1,
+            transport,
+            password: password.map(str::to_string),
+        })
+    }
+
+    async fn switch(transport: RpcTransport, password: Option<&str>) {
+        let actor = shelly(mock_shelly(password).await, transport, password);
+        assert!(!actor.state().await.unwrap());
+        actor.set(true).await.unwrap();
+        assert!(actor.state().await.unwrap());
+        actor.set(false).await.unwrap();
+        assert!(!actor.state().await.unwrap());
+        actor.set_on_for(Duration::from_millis(100)).await.unwrap();
+        assert!(actor.state().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!actor.state().await.unwrap());
+    }
+
+    async fn rejected(transport: RpcTransport, device: Option<&str>, password: Option<&str>) {
+        let actor = shelly(mock_shelly(device).await, transport, password);
+        assert!(actor.set(true).await.is_err());
+        assert!(actor.state().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shelly_gen2_http() {
+        switch(RpcTransport::Http, None).await;
+        switch(RpcTransport::Http, Some("secret")).await;
+        rejected(RpcTransport::Http, Some("secret"), None).await;
+        rejected(RpcTransport::Http, Some("secret"), Some("wrong")).await;
+    }
+
+    #[tokio::test]
+    async fn shelly_gen2_websocket() {
+        switch(RpcTransport::Websocket, None).await;
+        switch(RpcTransport::Websocket, Some("secret")).await;
+        rejected(RpcTransport::Websocket, Some("secret"), None).await;
+        rejected(RpcTransport::Websocket, Some("secret"), Some("wrong")).await;
+    }
+
+    #[tokio::test]
+    async fn shelly_gen2_websocket_timeout() {
+        // accepts the connection, but never answers the handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let actor = shelly(host, RpcTransport::Websocket, None);
+        tokio::time::pause();
+        let error = actor.state().await.unwrap_err();
+        assert!(
+            error.to_string().starts_with("Timeout connecting"),
+            "{error:#}"
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! Local HTTP API exposing the rooms as JSON and accepting heating commands.
+//!
+//! - `GET /api/rooms`
+//! - `GET /api/rooms/{name}`
+//! - `GET /api/rooms/{name}/history?from=..&to=..&resolution=raw|5m|1h|1d`
+//! - `PUT /api/rooms/{name}/state` with a body like `{"Auto": 21.0}` or `{"Manual": 3}`
+//! - `GET /metrics` in the Prometheus text format
+
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config::{ApiConfig, validate_heating_state};
+use crate::data::{ConnectionState, HeatingState, Room};
+use crate::schedule::ScheduleBlock;
+use crate::store::{HistoryReader, Period, Rollup};
+
+#[derive(Clone)]
+struct ApiState {
+    rooms: Arc<Mutex<Vec<Room>>>,
+    /// Path of the history database
+    database: String,
+}
+
+type ApiResult<T> = Result<Json<T>, (StatusCode, String)>;
+
+#[derive(serde::Serialize)]
+struct RoomView {
+    name: String,
+    sensor: Option<String>,
+    reading: Option<ReadingView>,
+    connection: Option<ConnectionState>,
+    window_open: bool,
+    battery: Option<u8>,
+    rssi: Option<i16>,
+    actor: Option<ActorView>,
+}
+
+#[derive(serde::Serialize)]
+struct ReadingView {
+    timestamp: Option<DateTime<Utc>>,
+    temperature: f32,
+    humidity: u8,
+}
+
+#[derive(serde::Serialize)]
+struct ActorView {
+    backend: String,
+    state: HeatingState,
+    /// Relay state as last read back
+    relay_on: Option<bool>,
+    last_error: Option<String>,
+    /// Safety interlock overriding the state right now
+    interlock: Option<&'static str>,
+    schedule: Vec<ScheduleBlock>,
+}
+
+impl RoomView {
+    fn new(room: &Room) -> Self {
+        Self {
+            name: room.name.clone(),
+            sensor: (!room.sensor_address.is_empty()).then(|| room.sensor_address.clone()),
+            reading: room.sensor.as_ref().map(|sensor| ReadingView {
+                timestamp: room.sensor_history.last().map(|item| item.timestamp),
+                temperature: sensor.temperature,
+                humidity: sensor.humidity,
+            }),
+            connection: room.connection,
+            window_open: room.window_detected(Instant::now()),
+            battery: room.battery,
+            rssi: room.rssi,
+            actor: room.actor.as_ref().map(|actor| ActorView {
+                backend: actor.backend.describe(),
+                state: actor.state,
+                relay_on: actor.status.confirmed.map(|(on, _)| on),
+                last_error: actor.status.last_error.as_ref().map(|(e, _)| e.clone()),
+                interlock: actor.safety.active.map(|i| i.describe()),
+                schedule: actor.schedule.clone(),
+            }),
+        }
+    }
+}
+
+fn not_found(name: &str) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("no room named '{name}'"))
+}
+
+async fn list_rooms(State(state): State<ApiState>) -> Json<Vec<RoomView>> {
+    let rooms = state.rooms.lock().unwrap();
+    Json(rooms.iter().map(RoomView::new).collect())
+}
+
+async fn get_room(State(state): State<ApiState>, Path(name): Path<String>) -> ApiResult<RoomView> {
+    let rooms = state.rooms.lock().unwrap();
+    let room = rooms
+        .iter()
+        .find(|r| r.name == name)
+        .ok_or_else(|| not_found(&name))?;
+    Ok(Json(RoomView::new(room)))
+}
+
+async fn put_state(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Json(heating): Json<HeatingState>,
+) -> ApiResult<RoomView> {
+    validate_heating_state(&heating).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let mut rooms = state.rooms.lock().unwrap();
+    let room = rooms
+        .iter_mut()
+        .find(|r| r.name == name)
+        .ok_or_else(|| not_found(&name))?;
+    let Some(actor) = &mut room.actor else {
+        return Err((StatusCode::CONFLICT, format!("room '{name}' has no actor")));
+    };
+    println!("{name}: heating state set to {heating:?} via API");
+    actor.state = heating;
+    Ok(Json(RoomView::new(room)))
+}
+
+#[derive(Clone, Copy, serde::Deserialize)]
+enum Resolution {
+    #[serde(rename = "raw")]
+    Raw,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    Hourly,
+    #[serde(rename = "1d")]
+    Daily,
+}
+
+impl Resolution {
+    /// Finest resolution that keeps a range of `span` at a manageable size.
+    fn for_span(span: TimeDelta) -> Self {
+        if span <= TimeDelta::days(2) {
+            Resolution::Raw
+        } else if span <= TimeDelta::days(31) {
+            Resolution::FiveMinutes
+        } else if span <= TimeDelta::days(366) {
+            Resolution::Hourly
+        } else {
+            Resolution::Daily
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    /// Start of the range, defaults to 24 hours before `to`
+    from: Option<DateTime<Utc>>,
+    /// End of the range, defaults to now
+    to: Option<DateTime<Utc>>,
+    resolution: Option<Resolution>,
+}
+
+#[derive(serde::Serialize)]
+struct HistoryPoint {
+    timestamp: DateTime<Utc>,
+    temperature: f32,
+    humidity: u8,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "resolution", content = "points")]
+enum HistoryView {
+    #[serde(rename = "raw")]
+    Raw(Vec<HistoryPoint>),
+    #[serde(rename = "5m")]
+    FiveMinutes(Vec<Rollup>),
+    #[serde(rename = "1h")]
+    Hourly(Vec<Rollup>),
+    #[serde(rename = "1d")]
+    Daily(Vec<Rollup>),
+}
+
+async fn get_history(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> ApiResult<HistoryView> {
+    let sensor = {
+        let rooms = state.rooms.lock().unwrap();
+        let room = rooms
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| not_found(&name))?;
+        room.sensor_address.clone()
+    };
+    if sensor.is_empty() {
+        return Err((StatusCode::CONFLICT, format!("room '{name}' has no sensor")));
+    }
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - TimeDelta::hours(24));
+    if from >= to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "'from' must be before 'to'".to_string(),
+        ));
+    }
+    let resolution = query.resolution.unwrap_or(Resolution::for_span(to - from));
+
+    let view = tokio::task::spawn_blocking(move || -> anyhow::Result<HistoryView> {
+        let reader = HistoryReader::open(&state.database)?;
+        Ok(match resolution {
+            Resolution::Raw => HistoryView::Raw(
+                reader
+                    .readings(&sensor, from, to)?
+                    .into_iter()
+                    .map(|sample| HistoryPoint {
+                        timestamp: sample.timestamp,
+                        temperature: sample.data.temperature,
+                        humidity: sample.data.humidity,
+                    })
+                    .collect(),
+            ),
+            Resolution::FiveMinutes => {
+                HistoryView::FiveMinutes(reader.rollups(&sensor, Period::FiveMinutes, from, to)?)
+            }
+            Resolution::Hourly => {
+                HistoryView::Hourly(reader.rollups(&sensor, Period::Hourly, from, to)?)
+            }
+            Resolution::Daily => {
+                HistoryView::Daily(reader.rollups(&sensor, Period::Daily, from, to)?)
+            }
+        })
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, format!("{e:#}")))?;
+    Ok(Json(view))
+}
+
+async fn metrics(
+    State(state): State<ApiState>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    let rooms = state.rooms.lock().unwrap();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render(&rooms),
+    )
+}
+
+fn router(rooms: Arc<Mutex<Vec<Room>>>, database: String) -> Router {
+    Router::new()
+        .route("/api/rooms", get(list_rooms))
+        .route("/api/rooms/{name}", get(get_room))
+        .route("/api/rooms/{name}/history", get(get_history))
+        .route("/api/rooms/{name}/state", put(put_state))
+        .route("/metrics", get(metrics))
+        .with_state(ApiState { rooms, database })
+}
+
+pub async fn serve(config: ApiConfig, rooms: Arc<Mutex<Vec<Room>>>, database: String) {
+    let app = router(rooms, database);
+
+    let listener = match tokio::net::TcpListener::bind(&config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("API: failed to listen on {}: {e}", config.listen);
+            return;
+        }
+    };
+    println!("API listening on {}", config.listen);
+    if let Err(e) = axum::serve(listener, app).await {
+        eprintln!("API: server failed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::data::{TPSensorData, create_rooms};
+    use crate::store::{HistoryConfig, Sample, Store};
+    use chrono::SecondsFormat;
+    use serde_json::Value;
+
+    const SENSOR: &str = "A4:C1:38:00:00:01";
+
+    /// Serves a room with sensor and actor and one without either on an
+    /// ephemeral port. Returns its base URL and the rooms.
+    async fn serve_rooms(database: &str) -> (String, Arc<Mutex<Vec<Room>>>) {
+        let config: Config = toml::from_str(&format!(
+            "[[room]]\nname = \"Kitchen\"\nsensor = \"{SENSOR}\"\n\
+             actor = {{ type = \"gpio\", pin = 17 }}\n\
+             [[room]]\nname = \"Hall\"\n"
+        ))
+        .unwrap();
+        let rooms = Arc::new(Mutex::new(create_rooms(&config, Vec::new(), None)));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = router(rooms.clone(), database.to_string());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, rooms)
+    }
+
+    async fn put(url: &str, body: &str) -> (u16, String) {
+        let response = reqwest::Client::new()
+            .put(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        (response.status().as_u16(), response.text().await.unwrap())
+    }
+
+    async fn get(url: &str) -> (u16, String) {
+        let response = reqwest::get(url).await.unwrap();
+        (response.status().as_u16(), response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn set_state() {
+        let (url, rooms) = serve_rooms("").await;
+        let state_url = format!("{url}/api/rooms/Kitchen/state");
+
+        let (status, body) = put(&state_url, r#"{"Manual": 3}"#).await;
+        assert_eq!(status, 200, "{body}");
+        let room: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(room["actor"]["state"], serde_json::json!({"Manual": 3}));
+        assert_eq!(
+            rooms.lock().unwrap()[0].actor.as_ref().unwrap().state,
+            HeatingState::Manual(3)
+        );
+
+        for (body, expected) in [
+            (r#"{"Manual": 7}"#, 422),
+            (r#"{"Manual": 300}"#, 422),
+            (r#"{"Auto": 35.0}"#, 422),
+            (r#"{"Auto": 4.9}"#, 422),
+            (r#"{"Eco": 1}"#, 422),
+            (r#"{"Auto": 21.0}"#, 200),
+        ] {
+            let (status, response) = put(&state_url, body).await;
+            assert_eq!(status, expected, "{body}: {response}");
+        }
+        assert_eq!(
+            rooms.lock().unwrap()[0].actor.as_ref().unwrap().state,
+            HeatingState::Auto(21.0)
+        );
+
+        let (status, body) = put(&format!("{url}/api/rooms/Attic/state"), r#"{"Manual": 3}"#).await;
+        assert_eq!((status, body.as_str()), (404, "no room named 'Attic'"));
+        let (status, _) = put(&format!("{url}/api/rooms/Hall/state"), r#"{"Manual": 3}"#).await;
+        assert_eq!(status, 409);
+        let (status, _) = get(&format!("{url}/api/rooms/Attic")).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn history_range() {
+        let database = std::env::temp_dir().join(format!("homectl-{}-api.db", std::process::id()));
+        let database = database.to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&database);
+        let config = HistoryConfig {
+            database: database.clone(),
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let mut store = Store::open(&config).unwrap();
+        store
+            .insert(&[Sample {
+                timestamp: now - TimeDelta::hours(1),
+                data: TPSensorData {
+                    address: SENSOR.to_string(),
+                    temperature: 20.5,
+                    humidity: 45,
+                },
+            }])
+            .unwrap();
+
+        let (url, _) = serve_rooms(&database).await;
+        let time = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let history = |query: String| {
+            let url = format!("{url}/api/rooms/Kitchen/history{query}");
+            async move { get(&url).await }
+        };
+
+        // the last 24 hours by default, in full resolution
+        let (status, body) = history(String::new()).await;
+        assert_eq!(status, 200, "{body}");
+        let view: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(view["resolution"], "raw");
+        assert_eq!(view["points"][0]["temperature"], 20.5);
+
+        // longer ranges are rolled up
+        let (status, body) = history(format!("?from={}", time(now - TimeDelta::days(10)))).await;
+        assert_eq!(status, 200, "{body}");
+        let view: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(view["resolution"], "5m");
+        let (_, body) = history(format!(
+            "?from={}&resolution=1d",
+            time(now - TimeDelta::days(1))
+        ))
+        .await;
+        let view: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(view["resolution"], "1d");
+
+        for query in [
+            format!("?from={}&to={}", time(now), time(now - TimeDelta::hours(1))),
+            format!("?to={}&from={0}", time(now)),
+            "?from=yesterday".to_string(),
+            "?resolution=2h".to_string(),
+        ] {
+            let (status, body) = history(query.clone()).await;
+            assert_eq!(status, 400, "{query}: {body}");
+        }
+
+        let (status, _) = get(&format!("{url}/api/rooms/Hall/history")).await;
+        assert_eq!(status, 409);
+        let (status, _) = get(&format!("{url}/api/rooms/Attic/history")).await;
+        assert_eq!(status, 404);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{database}{suffix}"));
+        }
+    }
+}
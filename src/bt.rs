@@ -1,155 +1,626 @@
+//! Bluetooth discovery and sensor connections. Everything is logged to stderr,
+//! so commands like `scan` can print their results to stdout.
+
 use bluer::{
-    Adapter, AdapterEvent, Address, Device, DiscoveryFilter, DiscoveryTransport,
-    gatt::remote::Characteristic,
+    Adapter, AdapterEvent, Address, Device, DeviceEvent, DeviceProperty, DiscoveryFilter,
+    DiscoveryTransport, Session, gatt::remote::Characteristic,
 };
-use futures::{StreamExt, pin_mut, stream::SelectAll};
-use std::{collections::HashSet, env};
+use chrono::Utc;
+use futures::{Stream, StreamExt, stream::SelectAll};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 
-use crate::data::TPSensorData;
+use crate::bthome::{self, BTHOME_UUID, BindKey, BtHomeReading};
+use crate::config::BluetoothConfig;
+use crate::data::{ConnectionState, SensorEvent, TPSensorData};
+use crate::sensors::{Frame, Measurement, SensorDriver, find_driver, timestamp_samples};
 
-async fn query_device(adapter: &Adapter, addr: Address) -> bluer::Result<Option<Characteristic>> {
+/// Returns the device and its driver if it is a supported sensor.
+async fn query_device(
+    adapter: &Adapter,
+    addr: Address,
+) -> bluer::Result<Option<(Device, &'static dyn SensorDriver)>> {
     let device = adapter.device(addr)?;
     let name = device.name().await?;
-    if name.is_some() && name.unwrap().starts_with("TP357") {
-        return query_tp(&device).await;
+    let uuids = device.uuids().await?.unwrap_or_default();
+    if let Some(driver) = find_driver(name.as_deref(), &uuids) {
+        eprintln!("{} found!", driver.model());
+        return Ok(Some((device, driver)));
     }
     Ok(None)
 }
 
-async fn query_tp(device: &Device) -> bluer::Result<Option<Characteristic>> {
-    println!("TP found!");
+/// Characteristics of a connected sensor
+struct SensorChars {
+    data: Characteristic,
+    command: Option<Characteristic>,
+    /// Standard battery level characteristic, if the sensor has one
+    battery: Option<Characteristic>,
+}
+
+/// Battery Level characteristic of the GATT battery service (0x2A19)
+const BATTERY_LEVEL_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a19_0000_1000_8000_00805f9b34fb);
+
+/// How often the battery level of connected sensors is read
+const BATTERY_INTERVAL: Duration = Duration::from_secs(3600);
 
+/// Minimum time between two RSSI updates of the same sensor
+const RSSI_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connects to the sensor and looks up the characteristics the driver needs.
+async fn connect_sensor(
+    device: &Device,
+    driver: &dyn SensorDriver,
+) -> bluer::Result<Option<SensorChars>> {
     if !device.is_connected().await? {
-        println!("    Connecting...");
-        let mut retries = 2;
+        eprintln!("    Connecting...");
+        let mut retries = driver.connect_retries();
         loop {
             match device.connect().await {
                 Ok(()) => break,
                 Err(err) if retries > 0 => {
-                    println!("    Connect error: {}", &err);
+                    eprintln!("    Connect error: {}", &err);
                     retries -= 1;
                 }
                 Err(err) => return Err(err),
             }
         }
-        println!("    Connected");
+        eprintln!("    Connected");
     } else {
-        println!("    Already connected");
+        eprintln!("    Already connected");
     }
 
-    println!("    Enumerating services...");
+    eprintln!("    Enumerating services...");
+    let mut data = None;
+    let mut command = None;
+    let mut battery = None;
     for service in device.services().await? {
         for char in service.characteristics().await? {
             let uuid = char.uuid().await?;
-            if uuid == uuid::Uuid::from_u128(0x000102030405060708090a0b0c0d2b10) {
-                println!("characteristic found");
-                return Ok(Some(char));
+            if uuid == driver.characteristic() {
+                eprintln!("characteristic found");
+                data = Some(char);
+            } else if Some(uuid) == driver.command_characteristic() {
+                command = Some(char);
+            } else if uuid == BATTERY_LEVEL_UUID {
+                battery = Some(char);
             }
         }
     }
 
-    Ok(None)
+    Ok(data.map(|data| SensorChars {
+        data,
+        command,
+        battery,
+    }))
 }
 
-pub async fn bt_main(tx: Sender<TPSensorData>) -> bluer::Result<()> {
-    let with_changes = env::args().any(|arg| arg == "--changes");
-    let le_only = env::args().any(|arg| arg == "--le");
-    let br_edr_only = env::args().any(|arg| arg == "--bredr");
-    let filter_addr: HashSet<_> = env::args()
-        .filter_map(|arg| arg.parse::<Address>().ok())
-        .collect();
+/// Resolves the configured adapter names or addresses, falling back to the
+/// default adapter if none of them is present.
+async fn select_adapters(
+    session: &Session,
+    config: &BluetoothConfig,
+) -> bluer::Result<Vec<Adapter>> {
+    let names = session.adapter_names().await?;
+    eprintln!("Adapters: {names:?}");
 
-    env_logger::init();
-    let session = bluer::Session::new().await?;
-    println!("Adapters: {:?}", session.adapter_names().await?);
+    let mut adapters: Vec<Adapter> = Vec::new();
+    for wanted in &config.adapters {
+        let mut found = None;
+        for name in &names {
+            let adapter = session.adapter(name)?;
+            let matches = match wanted.parse::<Address>() {
+                Ok(addr) => adapter.address().await? == addr,
+                Err(_) => name == wanted,
+            };
+            if matches {
+                found = Some(adapter);
+                break;
+            }
+        }
+        match found {
+            Some(adapter) if adapters.iter().any(|a| a.name() == adapter.name()) => (),
+            Some(adapter) => adapters.push(adapter),
+            None => eprintln!("Bluetooth adapter {wanted} not found"),
+        }
+    }
+
+    if adapters.is_empty() {
+        let adapter = session.default_adapter().await?;
+        eprintln!("Falling back to default adapter {}", adapter.name());
+        adapters.push(adapter);
+    }
+    Ok(adapters)
+}
+
+/// A device seen by at least one adapter that is not yet assigned to one.
+struct Candidate {
+    first_seen: Instant,
+    /// Signal strength per adapter index
+    rssi: HashMap<usize, Option<i16>>,
+}
+
+impl Candidate {
+    /// Index of the adapter that hears the device best.
+    fn best_adapter(&self) -> usize {
+        self.rssi
+            .iter()
+            .max_by_key(|(_, rssi)| rssi.unwrap_or(i16::MIN))
+            .map(|(idx, _)| *idx)
+            .unwrap_or_default()
+    }
+}
+
+/// Forwards the readings of a connected sensor until the connection fails.
+/// Returns `false` once the receiver is gone.
+async fn read_sensor(
+    addr: Address,
+    c: &SensorChars,
+    driver: &dyn SensorDriver,
+    tx: &Sender<SensorEvent>,
+) -> bool {
+    let reader = match c.data.notify_io().await {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("{addr}: notify failed: {e}");
+            return true;
+        }
+    };
+    eprintln!("{addr}: subscribed");
+
+    // fill the gap since we last heard from the sensor
+    if let (Some(request), Some(command)) = (driver.history_request(), &c.command) {
+        eprintln!("{addr}: requesting history");
+        if let Err(e) = command.write(request).await {
+            eprintln!("{addr}: history request failed: {e}");
+        }
+    }
+
+    let to_data = |m: Measurement| TPSensorData {
+        address: addr.to_string(),
+        temperature: m.temperature,
+        humidity: m.humidity,
+    };
+    let mut battery_timer = tokio::time::interval(BATTERY_INTERVAL);
+    loop {
+        let frame = tokio::select! {
+            frame = reader.recv() => frame,
+            _ = battery_timer.tick(), if c.battery.is_some() => {
+                let battery = c.battery.as_ref().unwrap();
+                match battery.read().await {
+                    Ok(value) if !value.is_empty() => {
+                        let event = SensorEvent::Battery {
+                            address: addr.to_string(),
+                            level: value[0],
+                        };
+                        if tx.send(event).await.is_err() {
+                            return false;
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => eprintln!("{addr}: reading battery level failed: {e}"),
+                }
+                continue;
+            }
+        };
+        match frame {
+            Ok(frame) => {
+                let event = match driver.decode(&frame) {
+                    Some(Frame::Live(m)) => SensorEvent::Reading(to_data(m)),
+                    Some(Frame::History(samples)) if !samples.is_empty() => {
+                        let samples = timestamp_samples(Utc::now(), samples)
+                            .into_iter()
+                            .map(|(timestamp, m)| (timestamp, to_data(m)))
+                            .collect();
+                        SensorEvent::History(samples)
+                    }
+                    _ => continue,
+                };
+                if tx.send(event).await.is_err() {
+                    return false;
+                }
+            }
+            Err(e) => {
+                eprintln!("{addr}: error from notify stream: {e:?}");
+                return true;
+            }
+        }
+    }
+}
 
-    let adapter = session.adapter("hci1")?;
-    println!(
-        "Discovering devices using Bluetooth adapter {}\n",
-        adapter.name()
-    );
-    adapter.set_powered(true).await?;
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Keeps a sensor connected: connects, subscribes to its readings and
+/// forwards them. Whenever the connection drops, it reconnects with
+/// exponential backoff. Returns once the receiver is gone.
+async fn supervise_device(
+    device: Device,
+    driver: &'static dyn SensorDriver,
+    tx: Sender<SensorEvent>,
+) {
+    let addr = device.address();
+    let address = addr.to_string();
+    let mut backoff = MIN_BACKOFF;
+    let report = |state| SensorEvent::Connection {
+        address: address.clone(),
+        state,
+    };
+
+    loop {
+        if tx.send(report(ConnectionState::Connecting)).await.is_err() {
+            return;
+        }
+        match connect_sensor(&device, driver).await {
+            Ok(Some(c)) => {
+                backoff = MIN_BACKOFF;
+                if tx.send(report(ConnectionState::Connected)).await.is_err() {
+                    return;
+                }
+                if !read_sensor(addr, &c, driver, &tx).await {
+                    return;
+                }
+            }
+            Ok(None) => eprintln!("{addr}: characteristic not found"),
+            Err(e) => eprintln!("{addr}: connect failed: {e}"),
+        }
+
+        if tx
+            .send(report(ConnectionState::Disconnected))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        // start over from a clean connection
+        let _ = device.disconnect().await;
+        eprintln!("{addr}: reconnecting in {}s", backoff.as_secs());
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Decodes a BTHome advertisement and forwards its contents. `last` keeps the
+/// previous packet and the merged values of each passive sensor.
+async fn handle_bthome(
+    addr: Address,
+    data: Vec<u8>,
+    key: Option<&BindKey>,
+    last: &mut HashMap<Address, (Vec<u8>, BtHomeReading)>,
+    tx: &Sender<SensorEvent>,
+) {
+    let (last_data, values) = last.entry(addr).or_default();
+    // the same packet is reported again by every adapter that hears it
+    if *last_data == data {
+        return;
+    }
+    let reading = match bthome::decode(addr.0, &data, key) {
+        Ok(reading) => reading,
+        Err(e) => {
+            eprintln!("{addr}: {e:#}");
+            return;
+        }
+    };
+    *last_data = data;
+    eprintln!("{addr}: BTHome {reading:?}");
+
+    let mut events = Vec::new();
+    if let Some(level) = reading.battery {
+        events.push(SensorEvent::Battery {
+            address: addr.to_string(),
+            level,
+        });
+    }
+
+    values.temperature = reading.temperature.or(values.temperature);
+    values.humidity = reading.humidity.or(values.humidity);
+
+    let address = addr.to_string();
+    if (reading.temperature.is_some() || reading.humidity.is_some())
+        && let (Some(temperature), Some(humidity)) = (values.temperature, values.humidity)
+    {
+        events.push(SensorEvent::Reading(TPSensorData {
+            address: address.clone(),
+            temperature,
+            humidity: humidity.round() as u8,
+        }));
+    }
+    if let Some(open) = reading.window_open {
+        events.push(SensorEvent::Window {
+            address: address.clone(),
+            open,
+        });
+    }
+    for event in reading.buttons {
+        events.push(SensorEvent::Button {
+            address: address.clone(),
+            event,
+        });
+    }
+    for event in events {
+        let _ = tx.send(event).await;
+    }
+}
+
+/// Property changes of a device, tagged with its address.
+async fn property_changes(
+    device: &Device,
+) -> bluer::Result<impl Stream<Item = (Address, DeviceEvent)> + use<>> {
+    let addr = device.address();
+    Ok(device.events().await?.map(move |evt| (addr, evt)))
+}
+
+/// How `bt_main` discovers devices, as given on the command line.
+#[derive(Debug, Clone)]
+pub struct DiscoveryOptions {
+    pub transport: DiscoveryTransport,
+    /// Print the property changes of every queried device
+    pub changes: bool,
+    /// Devices to consider, all if empty
+    pub only: HashSet<Address>,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            transport: DiscoveryTransport::Auto,
+            changes: false,
+            only: HashSet::new(),
+        }
+    }
+}
+
+pub async fn bt_main(
+    tx: Sender<SensorEvent>,
+    config: BluetoothConfig,
+    bindkeys: HashMap<Address, BindKey>,
+    options: DiscoveryOptions,
+) -> bluer::Result<()> {
+    let session = bluer::Session::new().await?;
+    let adapters = select_adapters(&session, &config).await?;
 
     let filter = DiscoveryFilter {
-        transport: if le_only {
-            DiscoveryTransport::Le
-        } else if br_edr_only {
-            DiscoveryTransport::BrEdr
-        } else {
-            DiscoveryTransport::Auto
-        },
+        transport: options.transport,
+        // passive sensors send a new advertisement with every reading
+        duplicate_data: true,
         ..Default::default()
     };
-    adapter.set_discovery_filter(filter).await?;
-    println!(
-        "Using discovery filter:\n{:#?}\n\n",
-        adapter.discovery_filter().await
-    );
 
-    let device_events = adapter.discover_devices().await?;
-    pin_mut!(device_events);
+    let mut device_events = SelectAll::new();
+    for (idx, adapter) in adapters.iter().enumerate() {
+        eprintln!(
+            "Discovering devices using Bluetooth adapter {}\n",
+            adapter.name()
+        );
+        adapter.set_powered(true).await?;
+        adapter.set_discovery_filter(filter.clone()).await?;
+        eprintln!(
+            "Using discovery filter:\n{:#?}\n\n",
+            adapter.discovery_filter().await
+        );
+        let events = adapter.discover_devices().await?;
+        device_events.push(Box::pin(events.map(move |evt| (idx, evt))));
+    }
+
+    // with a single adapter there is nothing to choose from
+    let assign_delay = if adapters.len() > 1 {
+        Duration::from_secs(config.assign_delay)
+    } else {
+        Duration::ZERO
+    };
+    let mut candidates: HashMap<Address, Candidate> = HashMap::new();
+    let mut assigned: HashMap<Address, usize> = HashMap::new();
+    let mut assign_timer = tokio::time::interval(Duration::from_secs(1));
 
     let mut all_change_events = SelectAll::new();
 
+    let mut passive: HashSet<(usize, Address)> = HashSet::new();
+    let mut passive_last = HashMap::new();
+    // property changes of all known sensors
+    let mut sensor_events = SelectAll::new();
+    let mut last_rssi: HashMap<Address, Instant> = HashMap::new();
+
     loop {
         tokio::select! {
-            Some(device_event) = device_events.next() => {
-                print!("Device Event {device_event:?} ");
+            Some((idx, device_event)) = device_events.next() => {
+                eprint!("Device Event {device_event:?} on {} ", adapters[idx].name());
                 match device_event {
                     AdapterEvent::DeviceAdded(addr) => {
-                        if !filter_addr.is_empty() && !filter_addr.contains(&addr) {
+                        if (!options.only.is_empty() && !options.only.contains(&addr))
+                            || assigned.contains_key(&addr)
+                        {
+                            eprintln!("… skipped");
                             continue;
                         }
-
-                        let res = query_device(&adapter, addr).await;
-                        if let Ok(Some(ref c)) = res {
-                            let tx = tx.clone();
-                            let c = c.clone();
-                            tokio::spawn(async move {
-                                let reader = c.notify_io().await.expect("notify failed");
-                                loop {
-                                    match reader.recv().await {
-                                        Ok(data) => {
-                                            if data.len() < 6 {
-                                                continue;
-                                            }
-                                            let temp = (data[3] as i32 + data[4] as i32 * 256) as f32 / 10.0;
-                                            let humidity = data[5] as u8;
-                                            tx.send(TPSensorData {
-                                                address: addr.to_string(),
-                                                temperature: temp,
-                                                humidity,
-                                            }).await.expect("Failed to send sensor data");
-                                        },
-                                        Err(e) => {
-                                            // try to reconnect
-                                            eprintln!("error from notify stream: {e:?}");
-                                            //reader = c.notify_io().await.expect("notify failed");
-                                        },
+                        let device = match adapters[idx].device(addr) {
+                            Ok(device) => device,
+                            Err(err) => {
+                                eprintln!("… error: {err}");
+                                continue;
+                            }
+                        };
+                        let service_data = device.service_data().await.unwrap_or_default();
+                        if let Some(data) = service_data.and_then(|mut d| d.remove(&BTHOME_UUID)) {
+                            if passive.insert((idx, addr)) {
+                                eprintln!("… BTHome sensor");
+                                handle_bthome(addr, data, bindkeys.get(&addr), &mut passive_last, &tx).await;
+                                match property_changes(&device).await {
+                                    Ok(events) => sensor_events.push(events),
+                                    Err(err) => {
+                                        eprintln!("{addr}: watching properties failed: {err}");
+                                        // try again when the device is reported next
+                                        passive.remove(&(idx, addr));
                                     }
                                 }
-                            });
+                            }
+                            continue;
                         }
-                        if let Err(err) = res {
-                            println!("    Error: {}", &err);
+                        let rssi = device.rssi().await.unwrap_or_default();
+                        candidates
+                            .entry(addr)
+                            .or_insert_with(|| Candidate {
+                                first_seen: Instant::now(),
+                                rssi: HashMap::new(),
+                            })
+                            .rssi
+                            .insert(idx, rssi);
+                        if !assign_delay.is_zero() {
+                            eprintln!("… waiting for other adapters");
+                            continue;
                         }
-
-                        if with_changes {
-                            let device = adapter.device(addr)?;
-                            let change_events = device.events().await?.map(move |evt| (addr, evt));
-                            all_change_events.push(change_events);
+                    }
+                    _ => {
+                        eprintln!("… done");
+                        continue;
+                    }
+                }
+            }
+            Some((addr, DeviceEvent::PropertyChanged(property))) = sensor_events.next() => {
+                match property {
+                    DeviceProperty::ServiceData(mut data) => {
+                        if let Some(data) = data.remove(&BTHOME_UUID) {
+                            handle_bthome(addr, data, bindkeys.get(&addr), &mut passive_last, &tx).await;
                         }
                     }
+                    DeviceProperty::Rssi(rssi)
+                        if last_rssi.get(&addr).is_none_or(|t| t.elapsed() >= RSSI_INTERVAL) =>
+                    {
+                        last_rssi.insert(addr, Instant::now());
+                        let _ = tx.send(SensorEvent::Rssi { address: addr.to_string(), rssi }).await;
+                    }
                     _ => (),
                 }
-                println!("… done");
             }
+            Some((addr, DeviceEvent::PropertyChanged(property))) = all_change_events.next() => {
+                eprintln!("Device changed: {addr}\n    {property:?}");
+            }
+            _ = assign_timer.tick() => (),
             else => {
-                println!("device event none!");
+                eprintln!("device event none!");
             },
         }
+
+        let due: Vec<Address> = candidates
+            .iter()
+            .filter(|(_, c)| c.first_seen.elapsed() >= assign_delay)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in due {
+            let Some(candidate) = candidates.remove(&addr) else {
+                continue;
+            };
+            let idx = candidate.best_adapter();
+            let adapter = &adapters[idx];
+            eprintln!(
+                "{addr}: using adapter {} ({:?})",
+                adapter.name(),
+                candidate.rssi
+            );
+
+            let res = query_device(adapter, addr).await;
+            match res {
+                Ok(Some((device, driver))) => {
+                    assigned.insert(addr, idx);
+                    if let Some(rssi) = candidate.rssi.get(&idx).copied().flatten() {
+                        let _ = tx
+                            .send(SensorEvent::Rssi {
+                                address: addr.to_string(),
+                                rssi,
+                            })
+                            .await;
+                    }
+                    match property_changes(&device).await {
+                        Ok(events) => sensor_events.push(events),
+                        Err(err) => eprintln!("{addr}: watching properties failed: {err}"),
+                    }
+                    tokio::spawn(supervise_device(device, driver, tx.clone()));
+                }
+                Ok(None) => (),
+                Err(err) => eprintln!("    Error: {}", &err),
+            }
+
+            if options.changes {
+                let change_events = match adapter.device(addr) {
+                    Ok(device) => property_changes(&device).await,
+                    Err(err) => Err(err),
+                };
+                match change_events {
+                    Ok(change_events) => all_change_events.push(change_events),
+                    Err(err) => eprintln!("{addr}: watching properties failed: {err}"),
+                }
+            }
+            eprintln!("… done");
+        }
     }
 
     //Ok(())
 }
+
+/// A supported sensor found by `scan`.
+pub struct FoundSensor {
+    pub address: Address,
+    pub name: Option<String>,
+    pub model: &'static str,
+    /// Best signal strength over all adapters
+    pub rssi: Option<i16>,
+}
+
+/// Discovers devices for `duration` and returns the supported sensors among
+/// them, strongest signal first.
+pub async fn scan(config: &BluetoothConfig, duration: Duration) -> bluer::Result<Vec<FoundSensor>> {
+    let session = bluer::Session::new().await?;
+    let adapters = select_adapters(&session, config).await?;
+
+    let mut device_events = SelectAll::new();
+    for (idx, adapter) in adapters.iter().enumerate() {
+        adapter.set_powered(true).await?;
+        let events = adapter.discover_devices().await?;
+        device_events.push(Box::pin(events.map(move |evt| (idx, evt))));
+    }
+
+    let mut found: HashMap<Address, FoundSensor> = HashMap::new();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        let (idx, addr) = tokio::select! {
+            _ = &mut deadline => break,
+            Some((idx, event)) = device_events.next() => match event {
+                AdapterEvent::DeviceAdded(addr) => (idx, addr),
+                _ => continue,
+            },
+            else => break,
+        };
+        let Ok(device) = adapters[idx].device(addr) else {
+            continue;
+        };
+        let name = device.name().await.unwrap_or_default();
+        let rssi = device.rssi().await.unwrap_or_default();
+        let bthome = device
+            .service_data()
+            .await
+            .unwrap_or_default()
+            .is_some_and(|data| data.contains_key(&BTHOME_UUID));
+        let model = if bthome {
+            "BTHome"
+        } else {
+            let uuids = device.uuids().await.unwrap_or_default().unwrap_or_default();
+            match find_driver(name.as_deref(), &uuids) {
+                Some(driver) => driver.model(),
+                None => continue,
+            }
+        };
+        let sensor = found.entry(addr).or_insert(FoundSensor {
+            address: addr,
+            name,
+            model,
+            rssi: None,
+        });
+        sensor.rssi = sensor.rssi.max(rssi);
+    }
+
+    let mut found: Vec<_> = found.into_values().collect();
+    found.sort_by_key(|sensor| std::cmp::Reverse(sensor.rssi.unwrap_or(i16::MIN)));
+    Ok(found)
+}
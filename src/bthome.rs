@@ -0,0 +1,223 @@
+//! Decoder for BTHome v2 advertisements (https://bthome.io/format/).
+
+use aes::Aes128;
+use anyhow::{Context, bail};
+use ccm::Ccm;
+use ccm::aead::{AeadInPlace, KeyInit};
+use ccm::consts::{U4, U13};
+use uuid::Uuid;
+
+/// Service data UUID of BTHome advertisements (0xFCD2)
+pub const BTHOME_UUID: Uuid = Uuid::from_u128(0x0000fcd2_0000_1000_8000_00805f9b34fb);
+
+pub type BindKey = [u8; 16];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonEvent {
+    Press,
+    DoublePress,
+    TriplePress,
+    LongPress,
+    LongDoublePress,
+    LongTriplePress,
+    HoldPress,
+}
+
+/// Everything we care about from one advertisement. Unset fields were not
+/// part of the packet.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BtHomeReading {
+    pub packet_id: Option<u8>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub battery: Option<u8>,
+    pub window_open: Option<bool>,
+    pub buttons: Vec<ButtonEvent>,
+}
+
+/// Data length of each object id, `None` for ids we don't know.
+fn object_len(id: u8) -> Option<usize> {
+    let len = match id {
+        0x00 | 0x01 | 0x09 | 0x0f..=0x11 | 0x15..=0x2f | 0x3a | 0x46 | 0x57..=0x59 | 0x60 => 1,
+        0x02 | 0x03 | 0x06..=0x08 | 0x0c..=0x0e | 0x12..=0x14 | 0x3c | 0x3d | 0x3f..=0x41 => 2,
+        0x43..=0x45 | 0x47..=0x4a | 0x51 | 0x52 | 0x56 | 0x5a | 0x5d..=0x5f | 0x61 | 0xf0 => 2,
+        0x04 | 0x05 | 0x0a | 0x0b | 0x42 | 0x4b | 0xf2 => 3,
+        0x3e | 0x4c..=0x50 | 0x55 | 0x5b | 0x5c | 0xf1 => 4,
+        _ => return None,
+    };
+    Some(len)
+}
+
+fn button_event(value: u8) -> Option<ButtonEvent> {
+    Some(match value {
+        0x01 => ButtonEvent::Press,
+        0x02 => ButtonEvent::DoublePress,
+        0x03 => ButtonEvent::TriplePress,
+        0x04 => ButtonEvent::LongPress,
+        0x05 => ButtonEvent::LongDoublePress,
+        0x06 => ButtonEvent::LongTriplePress,
+        0x80 => ButtonEvent::HoldPress,
+        _ => return None,
+    })
+}
+
+/// Parses a plain (decrypted) object list.
+fn decode_objects(mut data: &[u8]) -> anyhow::Result<BtHomeReading> {
+    let mut reading = BtHomeReading::default();
+    while let Some((&id, rest)) = data.split_first() {
+        // text and raw objects carry their own length
+        let len = match id {
+            0x53 | 0x54 => rest.first().map(|l| *l as usize + 1),
+            _ => object_len(id),
+        };
+        let Some(len) = len else {
+            // the length of an unknown object is unknown, so nothing after it can be read
+            bail!("unknown BTHome object id {id:#04x}");
+        };
+        if rest.len() < len {
+            bail!("truncated BTHome object {id:#04x}");
+        }
+        let (value, rest) = rest.split_at(len);
+        match id {
+            0x00 => reading.packet_id = Some(value[0]),
+            0x01 => reading.battery = Some(value[0]),
+            0x02 => {
+                reading.temperature = Some(i16::from_le_bytes([value[0], value[1]]) as f32 * 0.01)
+            }
+            0x03 => reading.humidity = Some(u16::from_le_bytes([value[0], value[1]]) as f32 * 0.01),
+            0x2d => reading.window_open = Some(value[0] != 0),
+            0x2e => reading.humidity = Some(value[0] as f32),
+            0x3a => reading.buttons.extend(button_event(value[0])),
+            0x45 => {
+                reading.temperature = Some(i16::from_le_bytes([value[0], value[1]]) as f32 * 0.1)
+            }
+            0x57 => reading.temperature = Some(value[0] as i8 as f32),
+            0x58 => reading.temperature = Some(value[0] as i8 as f32 * 0.35),
+            _ => (),
+        }
+        data = rest;
+    }
+    Ok(reading)
+}
+
+/// Decodes the BTHome service data of an advertisement sent by the device
+/// with the MAC address `mac`. Encrypted packets need the device's bindkey.
+pub fn decode(mac: [u8; 6], data: &[u8], key: Option<&BindKey>) -> anyhow::Result<BtHomeReading> {
+    let Some((&info, payload)) = data.split_first() else {
+        bail!("empty BTHome service data");
+    };
+    let version = info >> 5;
+    if version != 2 {
+        bail!("unsupported BTHome version {version}");
+    }
+    if info & 0x01 == 0 {
+        return decode_objects(payload);
+    }
+
+    let key = key.context("encrypted BTHome packet, but no bindkey configured")?;
+    // ciphertext, 4 byte counter, 4 byte MIC
+    if payload.len() < 8 {
+        bail!("encrypted BTHome packet too short");
+    }
+    let (ciphertext, rest) = payload.split_at(payload.len() - 8);
+    let (counter, mic) = rest.split_at(4);
+
+    let mut nonce = [0u8; 13];
+    nonce[..6].copy_from_slice(&mac);
+    nonce[6..8].copy_from_slice(&[0xd2, 0xfc]);
+    nonce[8] = info;
+    nonce[9..].copy_from_slice(counter);
+
+    let mut buffer = ciphertext.to_vec();
+    Ccm::<Aes128, U4, U13>::new(key.into())
+        .decrypt_in_place_detached(&nonce.into(), &[], &mut buffer, mic.into())
+        .ok()
+        .context("BTHome decryption failed, wrong bindkey?")?;
+    decode_objects(&buffer)
+}
+
+/// Parses a bindkey given as 32 hex digits.
+pub fn parse_bindkey(hex: &str) -> Option<BindKey> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 16];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device of the encryption example at https://bthome.io/encryption/
+    const MAC: [u8; 6] = [0x54, 0x48, 0xe6, 0x8f, 0x80, 0xa5];
+    const KEY: &str = "231d39c1d7cc1ab1aee224cd096db932";
+
+    fn assert_close(value: Option<f32>, expected: f32) {
+        let value = value.expect("value missing");
+        assert!((value - expected).abs() < 0.001, "{value} != {expected}");
+    }
+
+    #[test]
+    fn plain_packet() {
+        // battery 97%, temperature 25.06°C, humidity 50.55%
+        let data = [0x40, 0x01, 0x61, 0x02, 0xca, 0x09, 0x03, 0xbf, 0x13];
+        let reading = decode(MAC, &data, None).unwrap();
+        assert_eq!(reading.battery, Some(97));
+        assert_close(reading.temperature, 25.06);
+        assert_close(reading.humidity, 50.55);
+        assert_eq!(reading.packet_id, None);
+    }
+
+    #[test]
+    fn encrypted_packet() {
+        let data = [
+            0x41, 0xa4, 0x72, 0x66, 0xc9, 0x5f, 0x73, 0x00, 0x11, 0x22, 0x33, 0x78, 0x23, 0x72,
+            0x14,
+        ];
+        let key = parse_bindkey(KEY).unwrap();
+        let reading = decode(MAC, &data, Some(&key)).unwrap();
+        assert_close(reading.temperature, 25.06);
+        assert_close(reading.humidity, 50.55);
+
+        let mut wrong_key = key;
+        wrong_key[0] ^= 1;
+        assert!(decode(MAC, &data, Some(&wrong_key)).is_err());
+        assert!(decode(MAC, &data, None).is_err());
+        // the MAC is part of the nonce
+        assert!(decode([0; 6], &data, Some(&key)).is_err());
+        assert!(decode(MAC, &data[..8], Some(&key)).is_err());
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let packets: [&[u8]; 5] = [
+            &[],
+            // version 1
+            &[0x20, 0x01, 0x61],
+            // truncated temperature
+            &[0x40, 0x01, 0x61, 0x02, 0xca],
+            // unknown object id
+            &[0x40, 0x01, 0x61, 0xfe, 0x00],
+            // text object longer than the packet
+            &[0x40, 0x53, 0x05, 0x41],
+        ];
+        for data in packets {
+            assert!(decode(MAC, data, None).is_err(), "{data:02x?}");
+        }
+    }
+
+    #[test]
+    fn bindkeys() {
+        assert_eq!(
+            parse_bindkey(KEY).map(|key| key[..4].to_vec()),
+            Some(vec![0x23, 0x1d, 0x39, 0xc1])
+        );
+        assert_eq!(parse_bindkey(&KEY[1..]), None);
+        assert_eq!(parse_bindkey(&KEY.replace('d', "g")), None);
+        assert_eq!(parse_bindkey(&format!("ä{}", &KEY[2..])), None);
+    }
+}
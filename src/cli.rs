@@ -0,0 +1,419 @@
+//! Command line interface. `rooms` and `set` talk to a running instance
+//! through its HTTP API, the other commands work on their own.
+
+use anyhow::{Context, bail};
+use bluer::{Address, DiscoveryTransport};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::time::Duration;
+use tokio::sync::mpsc::channel;
+
+use crate::bt::{self, DiscoveryOptions};
+use crate::config::{Config, DEFAULT_CONFIG_PATH, validate_heating_state};
+use crate::data::{HeatingState, SensorEvent};
+use crate::store::{HistoryReader, Period};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Room thermometers and heating control",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    /// Config file
+    #[arg(long, global = true, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: String,
+    /// Options of `run`, which is the default
+    #[command(flatten)]
+    pub run: RunArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the controller with the panel GUI or headless
+    Run(RunArgs),
+    /// List nearby supported sensors with their signal strength
+    Scan {
+        /// Seconds to listen for devices
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+    },
+    /// Wait for one reading of a sensor and print it
+    Read {
+        /// MAC address of the sensor
+        address: Address,
+        /// Seconds to wait for the reading
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+    },
+    /// Print the current state of all rooms of the running instance
+    Rooms {
+        /// Print the API's JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Set the heating state of a room in the running instance
+    Set {
+        /// Name of the room
+        room: String,
+        mode: Mode,
+        /// Power level 0-6 in manual mode, target temperature in auto mode
+        value: String,
+    },
+    /// Write the stored sensor history as CSV to stdout
+    Export {
+        /// Only export this room
+        #[arg(long)]
+        room: Option<String>,
+        /// Start of the range (RFC 3339), defaults to 24 hours before `to`
+        #[arg(long)]
+        from: Option<DateTime<Utc>>,
+        /// End of the range (RFC 3339), defaults to now
+        #[arg(long)]
+        to: Option<DateTime<Utc>>,
+        /// Export min/avg/max rollups instead of single readings
+        #[arg(long)]
+        period: Option<ExportPeriod>,
+    },
+}
+
+#[derive(clap::Args, Default)]
+pub struct RunArgs {
+    /// Run without the GUI
+    #[arg(long)]
+    pub headless: bool,
+    /// Only discover Bluetooth LE devices
+    #[arg(long, conflicts_with = "bredr")]
+    pub le: bool,
+    /// Only discover Bluetooth classic devices
+    #[arg(long)]
+    pub bredr: bool,
+    /// Print the property changes of every queried device
+    #[arg(long)]
+    pub changes: bool,
+    /// Only use these sensors
+    pub addresses: Vec<Address>,
+}
+
+impl RunArgs {
+    pub fn discovery(&self) -> DiscoveryOptions {
+        DiscoveryOptions {
+            transport: if self.le {
+                DiscoveryTransport::Le
+            } else if self.bredr {
+                DiscoveryTransport::BrEdr
+            } else {
+                DiscoveryTransport::Auto
+            },
+            changes: self.changes,
+            only: self.addresses.iter().copied().collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Mode {
+    Manual,
+    Auto,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportPeriod {
+    #[value(name = "5m")]
+    FiveMinutes,
+    #[value(name = "1h")]
+    Hourly,
+    #[value(name = "1d")]
+    Daily,
+}
+
+fn runtime() -> anyhow::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Runtime::new().context("Unable to create Runtime")
+}
+
+pub fn scan(config: &Config, seconds: u64) -> anyhow::Result<()> {
+    let found = runtime()?.block_on(bt::scan(&config.bluetooth, Duration::from_secs(seconds)))?;
+    if found.is_empty() {
+        bail!("No supported sensors found");
+    }
+    for sensor in found {
+        let rssi = sensor
+            .rssi
+            .map_or("-".to_string(), |rssi| format!("{rssi} dBm"));
+        let name = sensor.name.unwrap_or_default();
+        let address = sensor.address.to_string();
+        let room = config
+            .rooms
+            .iter()
+            .find(|room| room.sensor_address().as_ref() == Some(&address))
+            .map_or("", |room| room.name.as_str());
+        println!(
+            "{}  {:>8}  {:<8}  {name:<16}  {room}",
+            sensor.address, rssi, sensor.model
+        );
+    }
+    Ok(())
+}
+
+pub fn read(config: &Config, address: Address, timeout: u64) -> anyhow::Result<()> {
+    runtime()?.block_on(async {
+        let (tx, mut rx) = channel(10);
+        let options = DiscoveryOptions {
+            only: [address].into(),
+            ..Default::default()
+        };
+        let bt = tokio::spawn(bt::bt_main(
+            tx,
+            config.bluetooth.clone(),
+            config.bindkeys(),
+            options,
+        ));
+        let reading = tokio::time::timeout(Duration::from_secs(timeout), async {
+            while let Some(event) = rx.recv().await {
+                if let SensorEvent::Reading(data) = event
+                    && data.address == address.to_string()
+                {
+                    return Some(data);
+                }
+            }
+            None
+        })
+        .await;
+        bt.abort();
+        match reading {
+            Ok(Some(data)) => {
+                println!("{address}: {:.1}°C {}%", data.temperature, data.humidity);
+                Ok(())
+            }
+            Ok(None) => match bt.await {
+                Ok(Err(e)) => Err(e).context("Bluetooth failed"),
+                _ => bail!("Bluetooth stopped without a reading"),
+            },
+            Err(_) => bail!("No reading from {address} within {timeout}s"),
+        }
+    })
+}
+
+/// Base URL of the running instance's API.
+fn api_url(config: &Config) -> anyhow::Result<String> {
+    let Some(api) = &config.api else {
+        bail!("The config has no [api] section, which is needed to reach the running instance");
+    };
+    let mut addr: std::net::SocketAddr = api.listen.parse().context("Invalid api.listen")?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(if addr.is_ipv4() {
+            std::net::Ipv4Addr::LOCALHOST.into()
+        } else {
+            std::net::Ipv6Addr::LOCALHOST.into()
+        });
+    }
+    Ok(format!("http://{addr}/api"))
+}
+
+/// Sends a request to the API and returns the JSON response.
+async fn api_request(request: reqwest::RequestBuilder) -> anyhow::Result<serde_json::Value> {
+    let response = request
+        .send()
+        .await
+        .context("Failed to reach the running instance")?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        bail!("{status}: {text}");
+    }
+    Ok(serde_json::from_str(&text)?)
+}
+
+fn room_line(room: &serde_json::Value) -> String {
+    let mut line = format!("{:<16}", room["name"].as_str().unwrap_or_default());
+    match room["reading"].as_object() {
+        Some(reading) => {
+            line += &format!(
+                " {:>6.1}°C {:>3}%",
+                reading["temperature"].as_f64().unwrap_or_default(),
+                reading["humidity"].as_u64().unwrap_or_default()
+            )
+        }
+        None => line += &format!(" {:>13}", "-"),
+    }
+    line += &format!("  {:<12}", room["connection"].as_str().unwrap_or(""));
+    if room["window_open"].as_bool() == Some(true) {
+        line += "  window open";
+    }
+    if let Some(actor) = room["actor"].as_object() {
+        let state = &actor["state"];
+        if let Some(target) = state["Auto"].as_f64() {
+            line += &format!("  auto {target:.1}°C");
+        } else if let Some(level) = state["Manual"].as_u64() {
+            line += &format!("  manual {level}");
+        }
+        match actor["relay_on"].as_bool() {
+            Some(true) => line += ", relay on",
+            Some(false) => line += ", relay off",
+            None => (),
+        }
+        if let Some(interlock) = actor["interlock"].as_str() {
+            line += &format!(", safety: {interlock}");
+        }
+        if let Some(error) = actor["last_error"].as_str() {
+            line += &format!(", error: {error}");
+        }
+    }
+    line
+}
+
+pub fn rooms(config: &Config, json: bool) -> anyhow::Result<()> {
+    let url = format!("{}/rooms", api_url(config)?);
+    let rooms = runtime()?.block_on(api_request(reqwest::Client::new().get(url)))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rooms)?);
+        return Ok(());
+    }
+    for room in rooms.as_array().into_iter().flatten() {
+        println!("{}", room_line(room));
+    }
+    Ok(())
+}
+
+pub fn set(config: &Config, room: &str, mode: Mode, value: &str) -> anyhow::Result<()> {
+    let state = match mode {
+        Mode::Manual => HeatingState::Manual(value.parse().context("Manual needs a level 0-6")?),
+        Mode::Auto => HeatingState::Auto(value.parse().context("Auto needs a target temperature")?),
+    };
+    validate_heating_state(&state).map_err(anyhow::Error::msg)?;
+    let mut url = reqwest::Url::parse(&api_url(config)?)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid API URL"))?
+        .extend(["rooms", room, "state"]);
+    let request = reqwest::Client::new()
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&state)?);
+    let room = runtime()?.block_on(api_request(request))?;
+    println!("{}", room_line(&room));
+    Ok(())
+}
+
+pub fn export(
+    config: &Config,
+    room: Option<&str>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    period: Option<ExportPeriod>,
+) -> anyhow::Result<()> {
+    let to = to.unwrap_or_else(Utc::now);
+    let from = from.unwrap_or(to - chrono::TimeDelta::hours(24));
+    if from >= to {
+        bail!("--from must be before --to");
+    }
+    let rooms: Vec<_> = config
+        .rooms
+        .iter()
+        .filter(|r| room.is_none_or(|name| r.name == name))
+        .filter_map(|r| Some((r.name.as_str(), r.sensor.clone()?)))
+        .collect();
+    if let Some(room) = room
+        && rooms.is_empty()
+    {
+        bail!("No room named '{room}' with a sensor");
+    }
+
+    let reader = HistoryReader::open(&config.history.database)?;
+    let period = period.map(|period| match period {
+        ExportPeriod::FiveMinutes => Period::FiveMinutes,
+        ExportPeriod::Hourly => Period::Hourly,
+        ExportPeriod::Daily => Period::Daily,
+    });
+    match period {
+        None => {
+            println!("room,sensor,timestamp,temperature,humidity");
+            for (name, sensor) in rooms {
+                for sample in reader.readings(&sensor, from, to)? {
+                    println!(
+                        "{},{sensor},{},{},{}",
+                        csv_field(name),
+                        sample.timestamp.to_rfc3339(),
+                        sample.data.temperature,
+                        sample.data.humidity
+                    );
+                }
+            }
+        }
+        Some(period) => {
+            println!(
+                "room,sensor,start,count,temperature_min,temperature_avg,temperature_max,humidity_min,humidity_avg,humidity_max"
+            );
+            for (name, sensor) in rooms {
+                for r in reader.rollups(&sensor, period, from, to)? {
+                    println!(
+                        "{},{sensor},{},{},{},{},{},{},{},{}",
+                        csv_field(name),
+                        r.start.to_rfc3339(),
+                        r.count,
+                        r.temperature_min,
+                        r.temperature_avg,
+                        r.temperature_max,
+                        r.humidity_min,
+                        r.humidity_avg,
+                        r.humidity_max
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Quotes a CSV field if needed.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(["homectl"].iter().chain(args))
+    }
+
+    #[test]
+    fn run_is_the_default() {
+        let cli = parse(&["--headless", "--le", "A4:C1:38:00:00:01"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.run.headless && cli.run.le && !cli.run.bredr);
+        assert_eq!(
+            cli.run.addresses,
+            ["A4:C1:38:00:00:01".parse::<Address>().unwrap()]
+        );
+
+        let cli = parse(&["--config", "other.toml"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(!cli.run.headless);
+        assert_eq!(cli.config, "other.toml");
+
+        let cli = parse(&["run", "--headless", "--bredr"]).unwrap();
+        let Some(Command::Run(args)) = cli.command else {
+            panic!("not run");
+        };
+        assert!(args.headless && args.bredr);
+    }
+
+    #[test]
+    fn subcommands() {
+        let cli = parse(&["scan", "--seconds", "5", "--config", "other.toml"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Scan { seconds: 5 })));
+        assert_eq!(cli.config, "other.toml");
+        let cli = parse(&["rooms"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Rooms { json: false })));
+        // run options only go with `run`
+        assert!(parse(&["--headless", "scan"]).is_err());
+        assert!(parse(&["--le", "--bredr"]).is_err());
+    }
+}
@@ -0,0 +1,605 @@
+use anyhow::{Context, bail};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::bthome::{BindKey, parse_bindkey};
+use crate::control::Controller;
+use crate::data::HeatingState;
+use crate::mqtt::MqttConfig;
+use crate::safety::SafetyConfig;
+use crate::schedule::{Preset, Presets, ScheduleBlock};
+use crate::store::HistoryConfig;
+
+pub const DEFAULT_CONFIG_PATH: &str = "homectl.toml";
+
+/// Static description of the house, loaded from `homectl.toml`.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// File holding the runtime state (heating states, edited schedules)
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    #[serde(default)]
+    pub bluetooth: BluetoothConfig,
+    #[serde(default)]
+    pub defaults: RoomDefaults,
+    /// Target temperatures of the schedule presets
+    #[serde(default)]
+    pub presets: Presets,
+    #[serde(default)]
+    pub away: AwayConfig,
+    #[serde(default)]
+    pub window: WindowConfig,
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// HTTP API, disabled if not given
+    pub api: Option<ApiConfig>,
+    /// MQTT broker readings and heating states are published to
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default, rename = "room")]
+    pub rooms: Vec<RoomConfig>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BluetoothConfig {
+    /// Adapters to scan with, by name (`hci0`) or address. If none is given
+    /// or none of them is present, the default adapter is used.
+    #[serde(default)]
+    pub adapters: Vec<String>,
+    /// Seconds to wait for all adapters to report a new device before it is
+    /// assigned to the adapter with the best signal.
+    #[serde(default = "default_assign_delay")]
+    pub assign_delay: u64,
+}
+
+impl Default for BluetoothConfig {
+    fn default() -> Self {
+        Self {
+            adapters: Vec::new(),
+            assign_delay: default_assign_delay(),
+        }
+    }
+}
+
+/// Values used for every room that does not set them itself.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomDefaults {
+    #[serde(default = "default_heating_state")]
+    pub state: HeatingState,
+    #[serde(default)]
+    pub controller: Controller,
+    /// Length of the cycle manual heating levels are spread over
+    #[serde(default = "default_cycle_minutes")]
+    pub cycle_minutes: u64,
+}
+
+impl Default for RoomDefaults {
+    fn default() -> Self {
+        Self {
+            state: default_heating_state(),
+            controller: Controller::default(),
+            cycle_minutes: default_cycle_minutes(),
+        }
+    }
+}
+
+/// Behaviour of the house-wide away mode.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AwayConfig {
+    /// Preset auto rooms are lowered to while away
+    pub preset: Preset,
+    /// Minutes before the return time at which rooms heat up again
+    pub preheat_minutes: u64,
+}
+
+impl Default for AwayConfig {
+    fn default() -> Self {
+        Self {
+            preset: Preset::Frost,
+            preheat_minutes: 180,
+        }
+    }
+}
+
+/// Open window detection from the room temperature.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Temperature fall in K that counts as an open window
+    pub drop: f32,
+    /// Period in minutes the fall has to happen in
+    pub minutes: u64,
+    /// Minutes heating stays paused after an open window was detected
+    pub pause_minutes: u64,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            drop: 1.0,
+            minutes: 5,
+            pause_minutes: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Address and port to listen on, e.g. `0.0.0.0:8080`
+    #[serde(default = "default_api_listen")]
+    pub listen: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub name: String,
+    /// MAC address of the room's thermometer
+    pub sensor: Option<String>,
+    /// Key of an encrypted BTHome sensor, 32 hex digits
+    pub bindkey: Option<String>,
+    pub actor: Option<ActorConfig>,
+    /// Weekly schedule switching the actor between presets
+    #[serde(default)]
+    pub schedule: Vec<ScheduleBlock>,
+}
+
+impl RoomConfig {
+    /// The sensor's MAC address written the way BlueZ reports it, so it
+    /// matches the addresses of the sensor events.
+    pub fn sensor_address(&self) -> Option<String> {
+        let sensor = self.sensor.as_ref()?;
+        Some(
+            sensor
+                .parse::<bluer::Address>()
+                .map_or_else(|_| sensor.clone(), |addr| addr.to_string()),
+        )
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ActorConfig {
+    #[serde(flatten)]
+    pub backend: ActorBackendConfig,
+    pub state: Option<HeatingState>,
+    pub controller: Option<Controller>,
+    pub cycle_minutes: Option<u64>,
+}
+
+/// Relay type and its connection settings, selected with `type = "..."`.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ActorBackendConfig {
+    /// Shelly Gen1 style relay URL, e.g. `http://shelly.local/relay/0`
+    ShellyGen1 { url: String },
+    /// Shelly Gen2/Gen3 switch, controlled via JSON-RPC
+    ShellyGen2 {
+        /// Host name, optionally with port, e.g. `shellypro3.local`
+        host: String,
+        /// Switch id on multi channel devices
+        #[serde(default)]
+        channel: u32,
+        #[serde(default)]
+        transport: RpcTransport,
+        /// Password of the device's `admin` user, if authentication is enabled
+        password: Option<String>,
+    },
+    /// Relay on a GPIO pin (sysfs numbering)
+    Gpio {
+        pin: u32,
+        #[serde(default)]
+        active_low: bool,
+    },
+    /// Relay switched by publishing to an MQTT topic
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+        command_topic: String,
+        /// Topic the relay reports its state on
+        state_topic: Option<String>,
+        #[serde(default = "default_payload_on")]
+        payload_on: String,
+        #[serde(default = "default_payload_off")]
+        payload_off: String,
+    },
+}
+
+fn default_state_file() -> String {
+    "rooms.json".to_string()
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcTransport {
+    #[default]
+    Http,
+    Websocket,
+}
+
+fn default_api_listen() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_payload_on() -> String {
+    "ON".to_string()
+}
+
+fn default_payload_off() -> String {
+    "OFF".to_string()
+}
+
+fn default_assign_delay() -> u64 {
+    5
+}
+
+fn default_cycle_minutes() -> u64 {
+    15
+}
+
+fn default_heating_state() -> HeatingState {
+    HeatingState::Manual(0)
+}
+
+pub fn load_config(path: impl AsRef<Path>) -> anyhow::Result<Config> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let config: Config = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+    config
+        .validate()
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    Ok(config)
+}
+
+pub fn validate_heating_state(state: &HeatingState) -> Result<(), String> {
+    match *state {
+        HeatingState::Manual(level) if level > 6 => {
+            Err(format!("manual level {level} is out of range 0-6"))
+        }
+        HeatingState::Auto(target) if !(5.0..=30.0).contains(&target) => Err(format!(
+            "target temperature {target} is out of range 5-30°C"
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_http_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        Ok(_) => Err(format!("actor url '{url}' must be http or https")),
+        Err(e) => Err(format!("actor url '{url}' is invalid: {e}")),
+    }
+}
+
+fn validate_backend(backend: &ActorBackendConfig) -> Result<(), String> {
+    match backend {
+        ActorBackendConfig::ShellyGen1 { url } => validate_http_url(url),
+        ActorBackendConfig::ShellyGen2 { host, .. } => {
+            validate_http_url(&format!("http://{host}/rpc"))
+                .map_err(|_| format!("invalid host '{host}'"))
+        }
+        ActorBackendConfig::Gpio { .. } => Ok(()),
+        ActorBackendConfig::Mqtt {
+            host,
+            username,
+            password,
+            command_topic,
+            ..
+        } => {
+            if host.is_empty() || command_topic.is_empty() {
+                Err("mqtt actor needs host and command_topic".to_string())
+            } else if username.is_some() != password.is_some() {
+                Err("mqtt actor needs both username and password".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+fn validate_cycle_minutes(minutes: u64) -> Result<(), String> {
+    if (1..=120).contains(&minutes) {
+        Ok(())
+    } else {
+        Err(format!("cycle_minutes {minutes} is out of range 1-120"))
+    }
+}
+
+fn validate_controller(controller: &Controller) -> Result<(), String> {
+    match *controller {
+        Controller::Hysteresis { hysteresis } if hysteresis <= 0.0 => {
+            Err("hysteresis must be positive".to_string())
+        }
+        Controller::Pi { kp, ki, cycle } if kp < 0.0 || ki < 0.0 || cycle == 0 => {
+            Err("pi parameters must not be negative and cycle must not be 0".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Config {
+    /// Bindkeys of the encrypted BTHome sensors by address.
+    pub fn bindkeys(&self) -> HashMap<bluer::Address, BindKey> {
+        self.rooms
+            .iter()
+            .filter_map(|room| {
+                let addr = room.sensor.as_ref()?.parse().ok()?;
+                let key = parse_bindkey(room.bindkey.as_ref()?)?;
+                Some((addr, key))
+            })
+            .collect()
+    }
+
+    /// Checks the config for problems and reports all of them at once.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+
+        if let Err(e) = validate_heating_state(&self.defaults.state) {
+            errors.push(format!("defaults: {e}"));
+        }
+        if let Err(e) = validate_controller(&self.defaults.controller) {
+            errors.push(format!("defaults: {e}"));
+        }
+        if let Err(e) = validate_cycle_minutes(self.defaults.cycle_minutes) {
+            errors.push(format!("defaults: {e}"));
+        }
+
+        for preset in Preset::ALL {
+            let target = self.presets.temperature(preset);
+            if let Err(e) = validate_heating_state(&HeatingState::Auto(target)) {
+                errors.push(format!("presets: {}: {e}", preset.name()));
+            }
+        }
+
+        if self.window.drop <= 0.0 || self.window.minutes == 0 || self.window.pause_minutes == 0 {
+            errors.push("window: drop, minutes and pause_minutes must be positive".to_string());
+        }
+
+        let safety = &self.safety;
+        if safety.frost_temperature >= safety.max_temperature {
+            errors.push("safety: frost_temperature must be below max_temperature".to_string());
+        }
+        if safety.max_on_minutes == 0 || safety.stale_sensor_minutes == 0 {
+            errors.push(
+                "safety: max_on_minutes and stale_sensor_minutes must be positive".to_string(),
+            );
+        }
+
+        if let Some(api) = &self.api
+            && api.listen.parse::<std::net::SocketAddr>().is_err()
+        {
+            errors.push(format!("api: '{}' is not a valid address:port", api.listen));
+        }
+        if let Some(mqtt) = &self.mqtt
+            && let Err(e) = mqtt.validate()
+        {
+            errors.push(format!("mqtt: {e}"));
+        }
+        if self.history.raw_days == 0 {
+            errors.push("history: raw_days must be at least 1".to_string());
+        }
+
+        let mut names = HashSet::new();
+        let mut sensors = HashSet::new();
+        for room in &self.rooms {
+            let name = &room.name;
+            if name.trim().is_empty() {
+                errors.push("room with empty name".to_string());
+            } else if !names.insert(name) {
+                errors.push(format!("room '{name}': defined more than once"));
+            }
+
+            if let Some(sensor) = &room.sensor {
+                match sensor.parse::<bluer::Address>() {
+                    Ok(addr) => {
+                        if !sensors.insert(addr) {
+                            errors.push(format!(
+                                "room '{name}': sensor {sensor} is used by another room"
+                            ));
+                        }
+                    }
+                    Err(_) => errors.push(format!(
+                        "room '{name}': sensor '{sensor}' is not a valid MAC address"
+                    )),
+                }
+            }
+
+            if let Some(key) = &room.bindkey {
+                if room.sensor.is_none() {
+                    errors.push(format!("room '{name}': bindkey given without sensor"));
+                }
+                if parse_bindkey(key).is_none() {
+                    errors.push(format!("room '{name}': bindkey must be 32 hex digits"));
+                }
+            }
+
+            if !room.schedule.is_empty() && room.actor.is_none() {
+                errors.push(format!("room '{name}': schedule given without actor"));
+            }
+            if room.schedule.iter().any(|block| block.days.is_empty()) {
+                errors.push(format!("room '{name}': schedule block without days"));
+            }
+
+            if let Some(actor) = &room.actor {
+                if let Err(e) = validate_backend(&actor.backend) {
+                    errors.push(format!("room '{name}': {e}"));
+                }
+                if let Some(Err(e)) = actor.state.as_ref().map(validate_heating_state) {
+                    errors.push(format!("room '{name}': {e}"));
+                }
+                if let Some(Err(e)) = actor.controller.as_ref().map(validate_controller) {
+                    errors.push(format!("room '{name}': {e}"));
+                }
+                if let Some(Err(e)) = actor.cycle_minutes.map(validate_cycle_minutes) {
+                    errors.push(format!("room '{name}': {e}"));
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(config: &str) -> Vec<String> {
+        let config: Config = toml::from_str(config).unwrap();
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(e) => e.to_string().lines().map(str::to_string).collect(),
+        }
+    }
+
+    fn parse_error(config: &str) -> String {
+        toml::from_str::<Config>(config).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn example_config() {
+        let config = load_config(DEFAULT_CONFIG_PATH).unwrap();
+        assert!(!config.rooms.is_empty());
+    }
+
+    #[test]
+    fn rooms() {
+        let config = r#"
+            [[room]]
+            name = "Kitchen"
+            sensor = "A4:C1:38:00:00:01"
+
+            [[room]]
+            name = "Kitchen"
+            sensor = "a4:c1:38:00:00:01"
+            bindkey = "1234"
+
+            [[room]]
+            name = " "
+            sensor = "kitchen"
+
+            [[room]]
+            name = "Hall"
+            bindkey = "231d39c1d7cc1ab1aee224cd096db932"
+            schedule = [{ days = [], start = "06:30", preset = "comfort" }]
+        "#;
+        assert_eq!(
+            errors(config),
+            [
+                "room 'Kitchen': defined more than once",
+                "room 'Kitchen': sensor a4:c1:38:00:00:01 is used by another room",
+                "room 'Kitchen': bindkey must be 32 hex digits",
+                "room with empty name",
+                "room ' ': sensor 'kitchen' is not a valid MAC address",
+                "room 'Hall': bindkey given without sensor",
+                "room 'Hall': schedule given without actor",
+                "room 'Hall': schedule block without days",
+            ]
+        );
+    }
+
+    #[test]
+    fn actors() {
+        let config = r#"
+            [[room]]
+            name = "Kitchen"
+            actor = { type = "shelly_gen1", url = "ftp://shelly.local/relay/0", state = { Manual = 7 } }
+
+            [[room]]
+            name = "Hall"
+            actor = { type = "shelly_gen2", host = "shelly pro", cycle_minutes = 0 }
+
+            [[room]]
+            name = "Bath"
+            actor = { type = "mqtt", host = "broker", command_topic = "", username = "homectl" }
+
+            [[room]]
+            name = "Attic"
+            actor = { type = "gpio", pin = 17, state = { Auto = 35.0 }, controller = { type = "pi", kp = -1.0, ki = 0.1, cycle = 600 } }
+        "#;
+        assert_eq!(
+            errors(config),
+            [
+                "room 'Kitchen': actor url 'ftp://shelly.local/relay/0' must be http or https",
+                "room 'Kitchen': manual level 7 is out of range 0-6",
+                "room 'Hall': invalid host 'shelly pro'",
+                "room 'Hall': cycle_minutes 0 is out of range 1-120",
+                "room 'Bath': mqtt actor needs host and command_topic",
+                "room 'Attic': target temperature 35 is out of range 5-30°C",
+                "room 'Attic': pi parameters must not be negative and cycle must not be 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let config = r#"
+            [defaults]
+            state = { Manual = 9 }
+            controller = { type = "hysteresis", hysteresis = 0.0 }
+
+            [presets]
+            frost = 2.0
+
+            [window]
+            minutes = 0
+
+            [safety]
+            frost_temperature = 30.0
+
+            [api]
+            listen = "localhost"
+        "#;
+        assert_eq!(
+            errors(config),
+            [
+                "defaults: manual level 9 is out of range 0-6",
+                "defaults: hysteresis must be positive",
+                "presets: frost: target temperature 2 is out of range 5-30°C",
+                "window: drop, minutes and pause_minutes must be positive",
+                "safety: frost_temperature must be below max_temperature",
+                "api: 'localhost' is not a valid address:port",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let error = parse_error("statefile = \"rooms.json\"\n");
+        assert!(error.contains("unknown field `statefile`"), "{error}");
+        let error = parse_error("[[room]]\nname = \"Hall\"\nsensors = \"A4:C1:38:00:00:01\"\n");
+        assert!(error.contains("unknown field `sensors`"), "{error}");
+        let error = parse_error("[safety]\nmax_temp = 25.0\n");
+        assert!(error.contains("unknown field `max_temp`"), "{error}");
+        let error = parse_error(
+            "[[room]]\nname = \"Hall\"\nactor = { type = \"gpio\", pin = 17, activelow = true }\n",
+        );
+        assert!(error.contains("unknown field `activelow`"), "{error}");
+        let error = parse_error(
+            "[[room]]\nname = \"Hall\"\nactor = { type = \"relay\", url = \"http://shelly.local\" }\n",
+        );
+        assert!(error.contains("unknown variant `relay`"), "{error}");
+        // schedules can only refer to the known presets
+        let error = parse_error(
+            "[[room]]\nname = \"Hall\"\nschedule = [{ days = [\"mon\"], start = \"06:30\", preset = \"warm\" }]\n",
+        );
+        assert!(error.contains("unknown variant `warm`"), "{error}");
+    }
+}
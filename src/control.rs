@@ -0,0 +1,200 @@
+use std::time::{Duration, Instant};
+
+/// Closed-loop controller used while a room is in `HeatingState::Auto`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Controller {
+    /// Two-point control: heat below `target - hysteresis / 2`, stop above
+    /// `target + hysteresis / 2`.
+    Hysteresis { hysteresis: f32 },
+    /// PI controller whose output is applied as a duty cycle of `cycle` seconds.
+    /// `kp` is in 1/K, `ki` in 1/(K*h).
+    Pi { kp: f32, ki: f32, cycle: u64 },
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller::Hysteresis { hysteresis: 0.5 }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ControllerState {
+    heating: bool,
+    integral: f32,
+    cycle_start: Option<Instant>,
+    on_time: Duration,
+}
+
+impl ControllerState {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Controller {
+    /// Returns whether the heating should be on at `now`. Without a current
+    /// `temperature` it is off, and the controller starts over.
+    pub fn update(
+        &self,
+        state: &mut ControllerState,
+        target: f32,
+        temperature: Option<f32>,
+        now: Instant,
+    ) -> bool {
+        let Some(temperature) = temperature else {
+            state.reset();
+            return false;
+        };
+        match *self {
+            Controller::Hysteresis { hysteresis } => {
+                if temperature < target - hysteresis / 2.0 {
+                    state.heating = true;
+                } else if temperature > target + hysteresis / 2.0 {
+                    state.heating = false;
+                }
+                state.heating
+            }
+            Controller::Pi { kp, ki, cycle } => {
+                let cycle = Duration::from_secs(cycle.max(1));
+                let cycle_done = state
+                    .cycle_start
+                    .is_none_or(|start| now.duration_since(start) >= cycle);
+                if cycle_done {
+                    let error = target - temperature;
+                    state.integral += error * cycle.as_secs_f32() / 3600.0;
+                    // anti windup: the integral part alone must stay within 0..=100%
+                    if ki > 0.0 {
+                        state.integral = state.integral.clamp(0.0, 1.0 / ki);
+                    } else {
+                        state.integral = 0.0;
+                    }
+                    let duty = (kp * error + ki * state.integral).clamp(0.0, 1.0);
+                    state.cycle_start = Some(now);
+                    state.on_time = cycle.mul_f32(duty);
+                }
+                let start = state.cycle_start.unwrap_or(now);
+                state.heating = now.duration_since(start) < state.on_time;
+                state.heating
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HYSTERESIS: Controller = Controller::Hysteresis { hysteresis: 0.5 };
+    const PI: Controller = Controller::Pi {
+        kp: 0.5,
+        ki: 1.0,
+        cycle: 600,
+    };
+
+    #[test]
+    fn hysteresis() {
+        // heating before, temperature, expected heating at a target of 20°C
+        let cases = [
+            (false, 19.7, true),
+            (false, 19.75, false),
+            (true, 19.75, true),
+            (false, 20.0, false),
+            (true, 20.0, true),
+            (true, 20.25, true),
+            (true, 20.3, false),
+            (false, 20.3, false),
+        ];
+        for (i, (heating, temperature, expected)) in cases.into_iter().enumerate() {
+            let mut state = ControllerState {
+                heating,
+                ..Default::default()
+            };
+            let on = HYSTERESIS.update(&mut state, 20.0, Some(temperature), Instant::now());
+            assert_eq!(on, expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn pi_duty() {
+        // error in K, expected on-time of the first cycle in seconds
+        let cases = [
+            (0.0, 0.0),
+            // proportional part 0.5, integral part 1/6
+            (1.0, 400.0),
+            // clamped to the whole cycle
+            (3.0, 600.0),
+            // neither part goes negative
+            (-1.0, 0.0),
+        ];
+        for (i, (error, expected)) in cases.into_iter().enumerate() {
+            let mut state = ControllerState::default();
+            PI.update(&mut state, 20.0, Some(20.0 - error), Instant::now());
+            let on_time = state.on_time.as_secs_f32();
+            assert!((on_time - expected).abs() < 0.01, "case {i}: {on_time}");
+        }
+    }
+
+    #[test]
+    fn pi_cycle() {
+        let mut state = ControllerState::default();
+        let start = Instant::now();
+        let at = |s| start + Duration::from_secs(s);
+        assert!(PI.update(&mut state, 20.0, Some(19.0), at(0)));
+        // the duty is only computed at the start of a cycle
+        assert!(PI.update(&mut state, 20.0, Some(25.0), at(399)));
+        assert!(!PI.update(&mut state, 20.0, Some(19.0), at(401)));
+        assert!(!PI.update(&mut state, 20.0, Some(25.0), at(600)));
+        assert_eq!(state.cycle_start, Some(at(600)));
+    }
+
+    #[test]
+    fn pi_windup() {
+        let mut state = ControllerState::default();
+        let start = Instant::now();
+        for cycle in 0..20 {
+            PI.update(
+                &mut state,
+                20.0,
+                Some(10.0),
+                start + Duration::from_secs(600 * cycle),
+            );
+        }
+        // the integral part alone is at most 100%
+        assert_eq!(state.integral, 1.0);
+        // so it doesn't keep heating long after the target was reached
+        PI.update(
+            &mut state,
+            20.0,
+            Some(20.1),
+            start + Duration::from_secs(600 * 20),
+        );
+        let duty = state.on_time.as_secs_f32() / 600.0;
+        assert!((duty - (1.0 - 0.1 / 6.0 - 0.05)).abs() < 1e-3, "{duty}");
+
+        // without an integral part, there is no integral
+        let p = Controller::Pi {
+            kp: 0.5,
+            ki: 0.0,
+            cycle: 600,
+        };
+        let mut state = ControllerState::default();
+        p.update(&mut state, 20.0, Some(19.0), start);
+        assert_eq!(state.integral, 0.0);
+        assert_eq!(state.on_time, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn stale_sensor() {
+        for controller in [HYSTERESIS, PI] {
+            let mut state = ControllerState::default();
+            let now = Instant::now();
+            assert!(controller.update(&mut state, 20.0, Some(15.0), now));
+            // no current reading: off, and the controller starts over
+            assert!(!controller.update(&mut state, 20.0, None, now));
+            assert!(!state.heating);
+            assert_eq!(state.integral, 0.0);
+            assert_eq!(state.cycle_start, None);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Everything that runs besides the GUI: sensor discovery, room updates,
+//! actors, persistence and the API. Used by the GUI and the headless mode.
+
+use eframe::egui::Context;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::channel;
+use tokio_util::sync::CancellationToken;
+
+use crate::bt::DiscoveryOptions;
+use crate::config::Config;
+use crate::data::{
+    Away, Room, create_rooms, load_state, save_state, switch_off_actors, update_actors,
+    update_rooms,
+};
+use crate::store::Store;
+
+/// Interval at which the headless mode saves the state
+const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Time the relays get to switch off on shutdown
+const SWITCH_OFF_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct Daemon {
+    pub ct: CancellationToken,
+    pub rooms: Arc<Mutex<Vec<Room>>>,
+    pub away: Arc<Mutex<Option<Away>>>,
+    state_file: String,
+    thread: Option<JoinHandle<()>>,
+}
+
+async fn redraw_loop(ctx: Context, sleep_time: Duration) {
+    loop {
+        ctx.request_repaint();
+        tokio::time::sleep(sleep_time).await;
+    }
+}
+
+/// Saves the state, logging instead of failing, so a full disk doesn't stop
+/// the heating.
+fn try_save(rooms: &Mutex<Vec<Room>>, away: &Mutex<Option<Away>>, state_file: &str) -> bool {
+    let result = save_state(&rooms.lock().unwrap(), *away.lock().unwrap(), state_file);
+    if let Err(e) = &result {
+        eprintln!("Failed to save the state to {state_file}: {e}");
+    }
+    result.is_ok()
+}
+
+async fn save_loop(
+    rooms: Arc<Mutex<Vec<Room>>>,
+    away: Arc<Mutex<Option<Away>>>,
+    state_file: String,
+) {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        try_save(&rooms, &away, &state_file);
+    }
+}
+
+/// Saves the state right away when it was changed remotely, e.g. via MQTT.
+async fn save_on_change(
+    changed: Arc<Notify>,
+    rooms: Arc<Mutex<Vec<Room>>>,
+    away: Arc<Mutex<Option<Away>>>,
+    state_file: String,
+) {
+    loop {
+        changed.notified().await;
+        try_save(&rooms, &away, &state_file);
+    }
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => (),
+                _ = term.recv() => (),
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+impl Daemon {
+    /// Loads the state and starts all tasks on a runtime in their own thread.
+    /// The GUI passes its `ctx` to be redrawn regularly. Without one, the
+    /// state is saved periodically instead of by the GUI.
+    pub fn start(config: &Config, ctx: Option<Context>, discovery: DiscoveryOptions) -> Self {
+        let state = load_state(&config.state_file);
+        let mut store = match Store::open(&config.history) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                eprintln!("Keeping no long-term history: {e:#}");
+                None
+            }
+        };
+        let rooms = Arc::new(Mutex::new(create_rooms(
+            config,
+            state.rooms,
+            store.as_mut(),
+        )));
+        let store_tx = store.map(Store::spawn);
+        let away = Arc::new(Mutex::new(state.away));
+
+        let rt = Runtime::new().expect("Unable to create Runtime");
+        let ct = CancellationToken::new();
+
+        let ct_clone = ct.clone();
+        let rooms_clone = rooms.clone();
+        let bt_config = config.bluetooth.clone();
+        let bindkeys = config.bindkeys();
+        let presets = config.presets;
+        let away_config = config.away;
+        let window_config = config.window;
+        let safety = config.safety;
+        let api_config = config.api.clone();
+        let mqtt_config = config.mqtt.clone();
+        let database = config.history.database.clone();
+        let state_file = config.state_file.clone();
+        let away_clone = away.clone();
+        let thread = std::thread::spawn(move || {
+            rt.block_on(async {
+                let (tx, rx) = channel(10);
+                let changed = Arc::new(Notify::new());
+                let mqtt = mqtt_config.as_ref().map(|mqtt| {
+                    crate::mqtt::Publisher::connect(
+                        mqtt,
+                        rooms_clone.clone(),
+                        presets,
+                        changed.clone(),
+                    )
+                });
+                tokio::spawn(save_on_change(
+                    changed,
+                    rooms_clone.clone(),
+                    away_clone.clone(),
+                    state_file.clone(),
+                ));
+                match ctx {
+                    Some(ctx) => {
+                        tokio::spawn(redraw_loop(ctx, Duration::from_secs(1)));
+                    }
+                    None => {
+                        tokio::spawn(save_loop(
+                            rooms_clone.clone(),
+                            away_clone.clone(),
+                            state_file,
+                        ));
+                    }
+                }
+                let handle = tokio::spawn(crate::bt::bt_main(tx, bt_config, bindkeys, discovery));
+                let update_rooms_handle = tokio::spawn(update_rooms(
+                    rx,
+                    rooms_clone.clone(),
+                    window_config,
+                    store_tx,
+                    mqtt.clone(),
+                ));
+                if let Some(api) = api_config {
+                    tokio::spawn(crate::api::serve(api, rooms_clone.clone(), database));
+                }
+                let mut update_actors_handle = tokio::spawn(update_actors(
+                    rooms_clone.clone(),
+                    away_clone,
+                    presets,
+                    away_config,
+                    safety,
+                    mqtt,
+                ));
+
+                tokio::select! {
+                    _ = shutdown_signal() => {
+                        println!("Signal received, shutting down");
+                        ct_clone.cancel();
+                    }
+                    _ = ct_clone.cancelled() => {
+                        println!("Cancellation requested, shutting down");
+                    }
+                    res = handle => {
+                        println!("shutdown bt");
+                        match res {
+                            Ok(Ok(())) => (),
+                            Ok(Err(err)) => eprintln!("Bluetooth error: {err}"),
+                            Err(err) => eprintln!("Error: {}", err),
+                        }
+                    }
+                    res = update_rooms_handle => {
+                        println!("shutdown rooms");
+                        if let Err(err) = res {
+                            eprintln!("Error in update_rooms: {}", err);
+                        }
+                    }
+                    res = &mut update_actors_handle => {
+                        println!("shutdown actors");
+                        if let Err(err) = res {
+                            eprintln!("Error in update_actors: {}", err);
+                        }
+                    }
+                }
+
+                // leave the heating off rather than in whatever state it is
+                if !update_actors_handle.is_finished() {
+                    update_actors_handle.abort();
+                    let _ = update_actors_handle.await;
+                }
+                let switch_off = switch_off_actors(&rooms_clone);
+                if tokio::time::timeout(SWITCH_OFF_TIMEOUT, switch_off)
+                    .await
+                    .is_err()
+                {
+                    eprintln!("Timeout switching the relays off");
+                }
+            })
+        });
+
+        Self {
+            ct,
+            rooms,
+            away,
+            state_file: config.state_file.clone(),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn save(&self) {
+        if try_save(&self.rooms, &self.away, &self.state_file) {
+            println!("State saved.");
+        }
+    }
+
+    /// Blocks until the tasks have stopped, then saves the state.
+    pub fn wait(mut self) {
+        self.join();
+    }
+
+    /// Stops the tasks, waits until they have switched the relays off and
+    /// saves the state.
+    pub fn stop(&mut self) {
+        self.ct.cancel();
+        self.join();
+    }
+
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("Runtime thread panicked");
+        }
+        self.save();
+    }
+}
@@ -1,8 +1,19 @@
-use eframe::egui::Context;
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta, Utc};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Receiver;
 
+use crate::actor::{Actor, create_actor};
+use crate::bthome::ButtonEvent;
+use crate::config::{AwayConfig, Config, WindowConfig};
+use crate::control::{Controller, ControllerState};
+use crate::history::History;
+use crate::mqtt::Publisher;
+use crate::safety::{SafetyConfig, SafetyState, SensorStatus};
+use crate::schedule::{self, Presets, ScheduleBlock};
+use crate::store::{Sample, Store};
+
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct TPSensorData {
     pub address: String,
@@ -10,252 +21,1147 @@ pub struct TPSensorData {
     pub humidity: u8,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// Messages from the bluetooth side to the rooms model.
+#[derive(Debug)]
+pub enum SensorEvent {
+    Reading(TPSensorData),
+    /// Samples read back from the sensor's own log
+    History(Vec<(DateTime<Utc>, TPSensorData)>),
+    Connection {
+        address: String,
+        state: ConnectionState,
+    },
+    Window {
+        address: String,
+        open: bool,
+    },
+    Button {
+        address: String,
+        event: ButtonEvent,
+    },
+    /// Battery level in percent
+    Battery {
+        address: String,
+        level: u8,
+    },
+    /// Signal strength in dBm
+    Rssi {
+        address: String,
+        rssi: i16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum HeatingState {
     Manual(u8), // power level 0-6
     Auto(f32),  // target temperature
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
 pub struct HeatingActor {
-    pub address: String,
+    pub backend: Arc<dyn Actor>,
     pub state: HeatingState,
+    pub controller: Controller,
+    /// Cycle the on-time of `HeatingState::Manual` is spread over
+    pub cycle: Duration,
+    pub schedule: Vec<ScheduleBlock>,
+    /// Whether the schedule was edited in the UI and overrides the config
+    pub schedule_edited: bool,
+    pub runtime: ActorRuntime,
+    pub status: ActorStatus,
+    pub safety: SafetyState,
+}
+
+#[derive(Debug, Default)]
+pub struct ActorRuntime {
+    pub controller: ControllerState,
+    /// State the actor was last driven with
+    pub applied: Option<HeatingState>,
+    /// Start of the current manual (fixed duty) cycle
+    pub cycle_start: Option<Instant>,
+    /// When the actor is evaluated next
+    pub next_tick: Option<Instant>,
+    /// Start of the schedule block that was applied last
+    pub schedule_block: Option<NaiveDateTime>,
+    /// Until when the relay is supposed to be on
+    pub on_until: Option<Instant>,
+    /// User set state last published to MQTT
+    pub published: Option<HeatingState>,
+}
+
+/// What we know about the relay's actual state.
+#[derive(Debug, Default, Clone)]
+pub struct ActorStatus {
+    /// Relay state as last read back, and when
+    pub confirmed: Option<(bool, Instant)>,
+    pub last_error: Option<(String, Instant)>,
+    /// Number of commands or read-backs that failed
+    pub failures: u64,
+    /// Total time the relay was confirmed on
+    pub on_time: Duration,
+}
+
+impl ActorStatus {
+    /// Records a read-back of the relay state. The time since the previous
+    /// read-back counts as on-time if the relay was on at both.
+    fn confirm(&mut self, on: bool, now: Instant) {
+        if let Some((true, at)) = self.confirmed
+            && on
+        {
+            self.on_time += now.duration_since(at);
+        }
+        self.confirmed = Some((on, now));
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Room {
     pub name: String,
     pub sensor_address: String,
-    #[serde(skip)]
     pub sensor_ttl: Option<std::time::Instant>,
     pub sensor: Option<TPSensorData>,
-    pub sensor_history: Vec<SensorHistoryItem>,
+    pub sensor_history: History,
+    pub connection: Option<ConnectionState>,
+    /// State of the room's window contact, if its sensor has one
+    pub window_open: Option<bool>,
+    /// Heating is paused until then, because the temperature fell sharply
+    pub window_pause_until: Option<Instant>,
+    /// Battery level of the sensor in percent
+    pub battery: Option<u8>,
+    /// Latest signal strength of the sensor in dBm
+    pub rssi: Option<i16>,
+    /// Number of times the sensor's connection was set up again after it dropped
+    pub reconnects: u64,
+    /// The connection was lost after it had been established, so the next
+    /// attempt counts as a reconnect
+    pub connection_dropped: bool,
     pub actor: Option<HeatingActor>,
 }
 
+impl Room {
+    /// Whether the window is open, as reported by a contact or detected from
+    /// the temperature.
+    pub fn window_detected(&self, now: Instant) -> bool {
+        self.window_open == Some(true) || self.window_pause_until.is_some_and(|until| now < until)
+    }
+
+    /// Updates the connection state and counts reconnects. Failed attempts
+    /// after a drop don't count.
+    pub fn set_connection(&mut self, state: ConnectionState) {
+        match state {
+            ConnectionState::Disconnected
+                if self.connection == Some(ConnectionState::Connected) =>
+            {
+                self.connection_dropped = true;
+            }
+            ConnectionState::Connecting if self.connection_dropped => {
+                self.reconnects += 1;
+                self.connection_dropped = false;
+            }
+            _ => (),
+        }
+        self.connection = Some(state);
+    }
+
+    /// Latest temperature and its age for the safety interlocks. Taken from
+    /// the history, as `sensor` is cleared once the reading gets old.
+    pub fn sensor_status(&self, now: DateTime<Utc>) -> SensorStatus {
+        if self.sensor_address.is_empty() {
+            return SensorStatus::None;
+        }
+        SensorStatus::Reading(self.sensor_history.last().map(|item| {
+            let age = (now - item.timestamp).to_std().unwrap_or_default();
+            (item.data.temperature, age)
+        }))
+    }
+}
+
+/// House-wide away mode. While it is active, rooms in auto mode are lowered
+/// to the away preset; their own states and schedules are left untouched.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Away {
+    /// Local time we are expected back, if known
+    pub until: Option<NaiveDateTime>,
+}
+
+impl Away {
+    /// Returns the target rooms are lowered to at `now`, or `None` once
+    /// pre-heating for the return has begun.
+    pub fn target(
+        &self,
+        config: &AwayConfig,
+        presets: &Presets,
+        now: NaiveDateTime,
+    ) -> Option<f32> {
+        let preheat = TimeDelta::minutes(config.preheat_minutes as i64);
+        if self.until.is_some_and(|until| now >= until - preheat) {
+            return None;
+        }
+        Some(presets.temperature(config.preset))
+    }
+}
+
+/// Contents of the state file.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct StateFile {
+    pub rooms: Vec<RoomState>,
+    #[serde(default)]
+    pub away: Option<Away>,
+}
+
+/// State files written before away mode only held the rooms.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum StoredState {
+    Current(StateFile),
+    Legacy(Vec<RoomState>),
+}
+
+/// The part of a `Room` that is persisted in the state file.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RoomState {
+    pub name: String,
+    /// Only read from state files written before the history database
+    #[serde(default, skip_serializing)]
+    pub sensor_history: Vec<SensorHistoryItem>,
+    #[serde(default)]
+    pub actor: Option<ActorState>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
+pub struct ActorState {
+    pub state: HeatingState,
+    /// Schedule as edited in the UI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Vec<ScheduleBlock>>,
+    /// Start of the schedule block applied last, so a state set by hand
+    /// outlasts a restart until the next block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule_block: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct SensorHistoryItem {
     pub data: TPSensorData,
-    #[serde(with = "approx_instant")]
-    pub timestamp: std::time::Instant,
+    #[serde(with = "timestamp")]
+    pub timestamp: DateTime<Utc>,
 }
 
-mod approx_instant {
-    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
-    use std::time::{Instant, SystemTime};
+/// Timestamps are written as RFC 3339. Older state files hold them as
+/// `SystemTime`, which is still accepted.
+mod timestamp {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::SystemTime;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Utc(DateTime<Utc>),
+        SystemTime(SystemTime),
+    }
 
-    pub fn serialize<S>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let system_now = SystemTime::now();
-        let instant_now = Instant::now();
-        let approx = system_now - (instant_now - *instant);
-        approx.serialize(serializer)
+        timestamp.serialize(serializer)
     }
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Instant, D::Error>
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let de = SystemTime::deserialize(deserializer)?;
-        let system_now = SystemTime::now();
-        let instant_now = Instant::now();
-        let duration = system_now.duration_since(de).map_err(Error::custom)?;
-        let approx = instant_now - duration;
-        Ok(approx)
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Utc(timestamp) => timestamp,
+            Stored::SystemTime(time) => time.into(),
+        })
     }
 }
 
-pub fn create_rooms() -> Vec<Room> {
-    let history = std::fs::File::open("rooms.json");
-    if let Ok(file) = history {
-        let reader = std::io::BufReader::new(file);
-        if let Ok(rooms) = serde_json::from_reader(reader) {
-            return rooms;
-        }
-    }
-
-    vec![
-        Room {
-            name: "Galerie".to_string(),
-            sensor_address: "10:76:36:76:66:1E".to_string(),
-            sensor_ttl: None,
-            sensor: None,
-            sensor_history: Vec::new(),
-            actor: None,
-        },
-        Room {
-            name: "Schlafzimmer".to_string(),
-            sensor_address: "D1:D7:3F:67:8C:EF".to_string(),
+pub fn create_rooms(
+    config: &Config,
+    states: Vec<RoomState>,
+    mut store: Option<&mut Store>,
+) -> Vec<Room> {
+    let mut rooms: Vec<Room> = config
+        .rooms
+        .iter()
+        .map(|room| Room {
+            name: room.name.clone(),
+            sensor_address: room.sensor_address().unwrap_or_default(),
             sensor_ttl: None,
             sensor: None,
-            sensor_history: Vec::new(),
-            actor: Some(HeatingActor {
-                address: "http://shellypro3-ece334ed1928.local/relay/2".to_string(),
-                state: HeatingState::Manual(3),
+            sensor_history: History::new(HISTORY_LEN),
+            connection: None,
+            window_open: None,
+            window_pause_until: None,
+            battery: None,
+            rssi: None,
+            reconnects: 0,
+            connection_dropped: false,
+            actor: room.actor.as_ref().map(|actor| HeatingActor {
+                backend: create_actor(&actor.backend),
+                state: actor.state.unwrap_or(config.defaults.state),
+                controller: actor
+                    .controller
+                    .clone()
+                    .unwrap_or_else(|| config.defaults.controller.clone()),
+                cycle: Duration::from_secs(
+                    60 * actor.cycle_minutes.unwrap_or(config.defaults.cycle_minutes),
+                ),
+                schedule: room.schedule.clone(),
+                schedule_edited: false,
+                runtime: ActorRuntime::default(),
+                status: ActorStatus::default(),
+                safety: SafetyState::default(),
             }),
-        },
-        // Room {
-        //     name: "Bad oben".to_string(),
-        //     sensor_address: "".to_string(),
-        //     sensor: None,
-        //     sensor_ttl: None,
-        //     actor: None,
-        // },
-        Room {
-            name: "Kinderzimmer".to_string(),
-            sensor_address: "D2:7C:11:BC:05:E3".to_string(),
-            sensor: None,
-            sensor_history: Vec::new(),
-            sensor_ttl: None,
-            actor: Some(HeatingActor {
-                address: "http://shellypro3-ece334ed1928.local/relay/0".to_string(),
-                state: HeatingState::Manual(0),
-            }),
-        },
-        // Room {
-        //     name: "Gäste-WC".to_string(),
-        //     sensor_address: "".to_string(),
-        //     sensor: None,
-        //     sensor_ttl: None,
-        //     actor: None,
-        // },
-        Room {
-            name: "Küche/Diele".to_string(),
-            sensor_address: "C9:B5:08:81:6A:AC".to_string(),
-            sensor: None,
-            sensor_history: Vec::new(),
-            sensor_ttl: None,
-            actor: None,
-        },
-        Room {
-            name: "Wohnzimmer".to_string(),
-            sensor_address: "FA:74:A7:99:89:04".to_string(),
-            sensor: None,
-            sensor_history: Vec::new(),
-            sensor_ttl: None,
-            actor: None,
-        },
-        // Room {
-        //     name: "Bad unten".to_string(),
-        //     sensor_address: "".to_string(),
-        //     sensor: None,
-        //     sensor_ttl: None,
-        //     actor: None,
-        // },
-        Room {
-            name: "Bäckerei".to_string(),
-            sensor_address: "10:76:36:C2:B7:87".to_string(),
-            sensor_ttl: None,
-            sensor: None,
-            sensor_history: Vec::new(),
-            actor: None,
-        },
-    ]
+        })
+        .collect();
+
+    if let Some(store) = &mut store {
+        // history of state files written before the history database
+        let legacy: Vec<_> = states
+            .iter()
+            .flat_map(|state| &state.sensor_history)
+            .map(|item| Sample {
+                timestamp: item.timestamp,
+                data: item.data.clone(),
+            })
+            .collect();
+        if !legacy.is_empty() {
+            match store.insert(&legacy) {
+                Ok(()) => println!("Imported {} samples from the state file", legacy.len()),
+                Err(e) => eprintln!("Failed to import history from the state file: {e:#}"),
+            }
+        }
+    }
+
+    for state in states {
+        let Some(room) = rooms.iter_mut().find(|r| r.name == state.name) else {
+            println!("Ignoring state of unknown room {}", state.name);
+            continue;
+        };
+        if store.is_none() {
+            for item in state.sensor_history {
+                room.sensor_history.push(item);
+            }
+        }
+        if let (Some(actor), Some(actor_state)) = (&mut room.actor, state.actor) {
+            actor.state = actor_state.state;
+            actor.runtime.schedule_block = actor_state.schedule_block;
+            if let Some(schedule) = actor_state.schedule {
+                actor.schedule = schedule;
+                actor.schedule_edited = true;
+            }
+        }
+    }
+
+    if let Some(store) = &store {
+        match store.readings_since(Utc::now() - HISTORY_LEN) {
+            Ok(samples) => {
+                for sample in samples {
+                    if let Some(room) = rooms
+                        .iter_mut()
+                        .find(|r| r.sensor_address == sample.data.address)
+                    {
+                        room.sensor_history.push(SensorHistoryItem {
+                            data: sample.data,
+                            timestamp: sample.timestamp,
+                        });
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to load history: {e:#}"),
+        }
+    }
+
+    rooms
+}
+
+/// Loads the state file, falling back to an empty state.
+pub fn load_state(path: &str) -> StateFile {
+    match load_state_file(path) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("Not restoring state from {path}: {e}");
+            StateFile::default()
+        }
+    }
+}
+
+fn load_state_file(path: &str) -> anyhow::Result<StateFile> {
+    let file = std::fs::File::open(path)?;
+    let reader = std::io::BufReader::new(file);
+    Ok(match serde_json::from_reader(reader)? {
+        StoredState::Current(state) => state,
+        StoredState::Legacy(rooms) => StateFile { rooms, away: None },
+    })
+}
+
+/// How far back the sensor history reaches
+pub const HISTORY_LEN: TimeDelta = TimeDelta::hours(24);
+
+/// Interval at which the actors are re-evaluated.
+const ACTOR_TICK: Duration = Duration::from_secs(30);
+
+/// Interval at which heating state changes (e.g. from the UI) are picked up
+const ACTOR_POLL: Duration = Duration::from_secs(1);
+
+/// Attempts to bring a relay into the expected state per tick
+const ACTOR_ATTEMPTS: u32 = 3;
+
+/// Time a relay gets to settle before its state is read back
+const ACTOR_SETTLE: Duration = Duration::from_secs(1);
+
+/// Sends the command for `on_until` if `send` is set, then reads back the
+/// relay and corrects it until it is in the expected state. Relays that
+/// can't be read back are only sent the command.
+async fn apply_actor(
+    name: &str,
+    backend: &dyn Actor,
+    on_until: Option<Instant>,
+    mut send: bool,
+    mut status: ActorStatus,
+) -> ActorStatus {
+    let expected = on_until.is_some_and(|t| Instant::now() < t);
+    let read_back = backend.can_read_back();
+    for attempt in 1..=ACTOR_ATTEMPTS {
+        if send {
+            let result = match on_until {
+                Some(t) if expected => backend.set_on_for(t - Instant::now()).await,
+                _ => backend.set(false).await,
+            };
+            if let Err(e) = result {
+                eprintln!("{name}: switching {} failed: {e:#}", backend.describe());
+                status.failures += 1;
+                status.last_error = Some((format!("{e:#}"), Instant::now()));
+                tokio::time::sleep(ACTOR_SETTLE).await;
+                continue;
+            }
+            if !read_back {
+                status.last_error = None;
+                return status;
+            }
+            tokio::time::sleep(ACTOR_SETTLE).await;
+        }
+        if !read_back {
+            return status;
+        }
+        match backend.state().await {
+            Ok(on) => {
+                status.confirm(on, Instant::now());
+                if on == expected {
+                    return status;
+                }
+                let error = format!("relay is {}, expected {}", on_off(on), on_off(expected));
+                println!("{name}: {error} (attempt {attempt})");
+                status.last_error = Some((error, Instant::now()));
+                send = true;
+            }
+            Err(e) => {
+                eprintln!(
+                    "{name}: reading state of {} failed: {e:#}",
+                    backend.describe()
+                );
+                status.failures += 1;
+                status.confirmed = None;
+                status.last_error = Some((format!("{e:#}"), Instant::now()));
+                // the command may well have worked, only read again
+                send = false;
+                tokio::time::sleep(ACTOR_SETTLE).await;
+            }
+        }
+    }
+    status
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+/// Switches all relays off. Used on shutdown, as no one would switch them
+/// off later.
+pub async fn switch_off_actors(rooms: &Mutex<Vec<Room>>) {
+    let actors: Vec<_> = rooms
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|room| Some((room.name.clone(), room.actor.as_ref()?.backend.clone())))
+        .collect();
+    let results =
+        futures::future::join_all(actors.iter().map(|(_, backend)| backend.set(false))).await;
+    for ((name, backend), result) in actors.iter().zip(results) {
+        match result {
+            Ok(()) => println!("{name}: switched {} off", backend.describe()),
+            Err(e) => eprintln!("{name}: switching {} off failed: {e:#}", backend.describe()),
+        }
+    }
 }
 
 pub async fn update_actors(
     rooms: Arc<Mutex<Vec<Room>>>,
+    away: Arc<Mutex<Option<Away>>>,
+    presets: Presets,
+    away_config: AwayConfig,
+    safety: SafetyConfig,
+    mqtt: Option<Publisher>,
 ) {
     println!("Starting update_actors loop");
-    let client = reqwest::ClientBuilder::new().build().unwrap();
     loop {
-        println!("updating actors now");
+        let local_now = Local::now().naive_local();
+        let away_target = match away.lock() {
+            Ok(mut away) => {
+                if let Some(Away { until: Some(until) }) = *away
+                    && local_now >= until
+                {
+                    println!("Back home, away mode ended");
+                    *away = None;
+                }
+                away.and_then(|away| away.target(&away_config, &presets, local_now))
+            }
+            Err(_) => None,
+        };
+
         let mut requests = Vec::new();
-        if let Ok(rooms) = rooms.lock() {
-            for room in &*rooms {
-                if let Some(actor) = &room.actor {
-                    println!("found actor!");
-                    match actor.state {
-                        HeatingState::Manual(level) => {
-                            if level == 0 {
-                                let url = &actor.address;
-                                let query = [("turn", "off")];
-                                let request = client.get(url).query(&query);
-                                println!("Sending request: {request:?}");
-                                requests.push(request.send());
-                            } else {
-                                let time = level as u32 * 3600/6;
-                                let url = &actor.address;
-                                let query = [("turn", "on"), ("timer", &format!("{time}"))];
-                                let request = client.get(url).query(&query);
-                                println!("Sending request: {request:?}");
-                                requests.push(request.send());
-                            }
-                        },
-                        HeatingState::Auto(_) => unimplemented!()
+        if let Ok(mut rooms) = rooms.lock() {
+            let now = Instant::now();
+            for room in &mut *rooms {
+                let window_open = room.window_detected(now);
+                let sensor = room.sensor_status(Utc::now());
+                let Some(actor) = &mut room.actor else {
+                    continue;
+                };
+                // a new schedule block replaces whatever was set by hand
+                if let Some((start, preset)) = schedule::active(&actor.schedule, local_now)
+                    && actor.runtime.schedule_block != Some(start)
+                {
+                    println!("{}: schedule switches to {}", room.name, preset.name());
+                    actor.state = HeatingState::Auto(presets.temperature(preset));
+                    actor.runtime.schedule_block = Some(start);
+                }
+                if let Some(mqtt) = &mqtt
+                    && actor.runtime.published != Some(actor.state)
+                {
+                    mqtt.climate(&room.name, actor.state);
+                    actor.runtime.published = Some(actor.state);
+                }
+                // the state actually driven, with away mode and open windows applied
+                let state = match (actor.state, away_target) {
+                    _ if window_open => HeatingState::Manual(0),
+                    (HeatingState::Auto(target), Some(away)) => {
+                        HeatingState::Auto(target.min(away))
+                    }
+                    (state, _) => state,
+                };
+                let changed = actor.runtime.applied != Some(state);
+                if changed {
+                    println!("{}: heating state changed to {:?}", room.name, state);
+                    if let Some(mqtt) = &mqtt {
+                        mqtt.heating(&room.name, state);
+                    }
+                    if !matches!(actor.runtime.applied, Some(HeatingState::Manual(_))) {
+                        actor.runtime.cycle_start = None;
+                    }
+                    if !matches!(actor.runtime.applied, Some(HeatingState::Auto(_))) {
+                        actor.runtime.controller.reset();
                     }
+                    actor.runtime.applied = Some(state);
+                } else if actor.runtime.next_tick.is_some_and(|next| now < next) {
+                    continue;
                 }
+                actor.runtime.next_tick = Some(now + ACTOR_TICK);
+
+                let on_until = match state {
+                    // fully on, keep the relay's timer running like in auto mode
+                    HeatingState::Manual(6) => Some(now + 3 * ACTOR_TICK),
+                    HeatingState::Manual(level) => {
+                        // a level change keeps the running cycle, so the
+                        // on-time stays spread evenly
+                        let cycle = actor.cycle;
+                        let start = match actor.runtime.cycle_start {
+                            Some(start) if now < start + cycle => start,
+                            _ => now,
+                        };
+                        actor.runtime.cycle_start = Some(start);
+                        (level > 0).then(|| start + cycle * level as u32 / 6)
+                    }
+                    HeatingState::Auto(target) => {
+                        let temperature = room.sensor.as_ref().map(|sensor| sensor.temperature);
+                        if temperature.is_none() {
+                            // no current reading, fail safe
+                            println!("{}: no sensor data, heating off", room.name);
+                        }
+                        let on = actor.controller.update(
+                            &mut actor.runtime.controller,
+                            target,
+                            temperature,
+                            now,
+                        );
+                        // keep the relay's own timer running a few ticks ahead, so it
+                        // switches off by itself if we stop refreshing it
+                        on.then(|| now + 3 * ACTOR_TICK)
+                    }
+                };
+                let want_on = on_until.is_some_and(|until| now < until);
+                let on = safety.check(&room.name, &mut actor.safety, want_on, sensor, now);
+                let on_until = match (want_on, on) {
+                    (true, false) => None,
+                    (false, true) => Some(now + 3 * ACTOR_TICK),
+                    _ => on_until,
+                };
+                // a new command is only sent if the schedule changed, otherwise
+                // the relay is just checked against `on_until`
+                let send = changed || on_until != actor.runtime.on_until;
+                actor.runtime.on_until = on_until;
+
+                requests.push((
+                    room.name.clone(),
+                    actor.backend.clone(),
+                    actor.runtime.on_until,
+                    send,
+                    actor.status.clone(),
+                ));
             }
         }
-        for request in requests {
-            match request.await {
-                Ok(response) => println!("{response:?}"),
-                Err(e) => eprintln!("{e}")
+
+        let results = futures::future::join_all(requests.into_iter().map(
+            |(name, backend, on_until, send, status)| async move {
+                let status = apply_actor(&name, &*backend, on_until, send, status).await;
+                (name, status)
+            },
+        ))
+        .await;
+
+        if let Ok(mut rooms) = rooms.lock() {
+            for (name, status) in results {
+                if let Some(actor) = rooms
+                    .iter_mut()
+                    .find(|r| r.name == name)
+                    .and_then(|r| r.actor.as_mut())
+                {
+                    actor.status = status;
+                }
             }
         }
-        println!("done updating actors, see you in 1h!");
-        tokio::time::sleep(Duration::from_secs(3600)).await;
+        tokio::time::sleep(ACTOR_POLL).await;
     }
 }
 
 pub async fn update_rooms(
-    mut rx: Receiver<TPSensorData>,
+    mut rx: Receiver<SensorEvent>,
     rooms: Arc<Mutex<Vec<Room>>>,
-    ctx: Context,
+    window: WindowConfig,
+    store: Option<Sender<Vec<Sample>>>,
+    mqtt: Option<Publisher>,
 ) {
     loop {
-        let sensor = rx.recv().await;
+        let event = rx.recv().await;
 
-        let sensor = match sensor {
-            Some(s) => s,
+        let sensor = match event {
+            Some(SensorEvent::Reading(s)) => s,
+            Some(SensorEvent::Connection { address, state }) => {
+                println!("{address}: {state:?}");
+                let mut rooms = rooms.lock().unwrap();
+                if let Some(room) = rooms.iter_mut().find(|r| r.sensor_address == address) {
+                    room.set_connection(state);
+                }
+                continue;
+            }
+            Some(SensorEvent::Window { address, open }) => {
+                println!("{address}: window open: {open}");
+                let mut rooms = rooms.lock().unwrap();
+                if let Some(room) = rooms.iter_mut().find(|r| r.sensor_address == address) {
+                    room.window_open = Some(open);
+                }
+                continue;
+            }
+            Some(SensorEvent::Button { address, event }) => {
+                println!("{address}: button {event:?}");
+                continue;
+            }
+            Some(SensorEvent::Battery { address, level }) => {
+                println!("{address}: battery {level}%");
+                let mut rooms = rooms.lock().unwrap();
+                if let Some(room) = rooms.iter_mut().find(|r| r.sensor_address == address) {
+                    room.battery = Some(level);
+                }
+                continue;
+            }
+            Some(SensorEvent::Rssi { address, rssi }) => {
+                let mut rooms = rooms.lock().unwrap();
+                if let Some(room) = rooms.iter_mut().find(|r| r.sensor_address == address) {
+                    room.rssi = Some(rssi);
+                }
+                continue;
+            }
+            Some(SensorEvent::History(samples)) => {
+                let mut rooms = rooms.lock().unwrap();
+                let added = backfill_history(&mut rooms, samples);
+                if let Some(store) = &store {
+                    let samples = added
+                        .into_iter()
+                        .map(|(timestamp, data)| Sample { timestamp, data })
+                        .collect();
+                    let _ = store.send(samples);
+                }
+                continue;
+            }
             None => continue,
         };
+        if let Some(store) = &store {
+            let _ = store.send(vec![Sample {
+                timestamp: Utc::now(),
+                data: sensor.clone(),
+            }]);
+        }
+
         let mut rooms = rooms.lock().unwrap();
 
         // update rooms list with new sensor data
-        let history_len = Duration::from_secs(24 * 60 * 60);
 
         if let Some(existing) = rooms
             .iter_mut()
             .find(|s| s.sensor_address == sensor.address)
         {
+            let now = Instant::now();
+            if !existing.window_detected(now)
+                && temperature_drop(&existing.sensor_history, sensor.temperature, &window)
+            {
+                println!("{}: temperature drops fast, window open?", existing.name);
+                existing.window_pause_until =
+                    Some(now + Duration::from_secs(60 * window.pause_minutes));
+            }
+            if let Some(mqtt) = &mqtt {
+                mqtt.reading(&existing.name, &sensor);
+            }
             existing.sensor = Some(sensor.clone());
             existing.sensor_history.push(SensorHistoryItem {
                 data: sensor,
-                timestamp: Instant::now(),
+                timestamp: Utc::now(),
             });
-            while existing.sensor_history[0].timestamp < Instant::now() - history_len {
-                existing.sensor_history.remove(0);
-            }
             existing.sensor_ttl = Some(Instant::now() + std::time::Duration::from_secs(300));
         } else {
+            if let Some(mqtt) = &mqtt {
+                mqtt.reading(&sensor.address, &sensor);
+            }
             rooms.push(Room {
                 name: sensor.address.clone(),
                 sensor_address: sensor.address.clone(),
                 sensor_ttl: Some(Instant::now() + std::time::Duration::from_secs(300)),
                 sensor: Some(sensor),
-                sensor_history: vec![],
+                sensor_history: History::new(HISTORY_LEN),
+                connection: Some(ConnectionState::Connected),
+                window_open: None,
+                window_pause_until: None,
+                battery: None,
+                rssi: None,
+                reconnects: 0,
+                connection_dropped: false,
                 actor: None,
             });
+            if let Some(mqtt) = &mqtt {
+                mqtt.announce_room(rooms.last().unwrap());
+            }
         }
 
         // Remove stale sensors
         for room in &mut *rooms {
-            if let Some(ttl) = room.sensor_ttl {
-                if Instant::now() > ttl {
-                    room.sensor = None;
-                    room.sensor_ttl = None;
-                }
+            if let Some(ttl) = room.sensor_ttl
+                && Instant::now() > ttl
+            {
+                room.sensor = None;
+                room.sensor_ttl = None;
             }
         }
-//        ctx.request_repaint();
     }
 }
 
-pub fn save_rooms_to_file(rooms: &Vec<Room>, path: &str) {
-    let history_file = std::fs::File::create(path).unwrap();
+/// Whether `temperature` is lower than the warmest sample of the last
+/// `window.minutes` by at least `window.drop`.
+fn temperature_drop(history: &History, temperature: f32, window: &WindowConfig) -> bool {
+    let since = Utc::now() - TimeDelta::minutes(window.minutes as i64);
+    history
+        .since(since)
+        .any(|item| item.data.temperature - temperature >= window.drop)
+}
+
+/// Samples closer than this to an existing one are considered duplicates.
+const HISTORY_RESOLUTION: TimeDelta = TimeDelta::seconds(30);
+
+/// Inserts samples from a sensor's log into the gaps of the rooms' history.
+/// Returns the samples that were added.
+fn backfill_history(
+    rooms: &mut [Room],
+    samples: Vec<(DateTime<Utc>, TPSensorData)>,
+) -> Vec<(DateTime<Utc>, TPSensorData)> {
+    let history_start = Utc::now() - HISTORY_LEN;
+    let mut added = Vec::new();
+    for (timestamp, data) in samples {
+        if timestamp < history_start {
+            continue;
+        }
+        let Some(room) = rooms.iter_mut().find(|r| r.sensor_address == data.address) else {
+            continue;
+        };
+        let item = SensorHistoryItem {
+            data: data.clone(),
+            timestamp,
+        };
+        if room
+            .sensor_history
+            .insert_if_missing(item, HISTORY_RESOLUTION)
+        {
+            added.push((timestamp, data));
+        }
+    }
+    println!("backfilled {} history samples", added.len());
+    added
+}
+
+/// Writes the state file. It is replaced only once the new state is written
+/// completely, so a failed write keeps the previous one.
+pub fn save_state(rooms: &[Room], away: Option<Away>, path: &str) -> std::io::Result<()> {
+    let rooms: Vec<_> = rooms
+        .iter()
+        .map(|room| RoomState {
+            name: room.name.clone(),
+            sensor_history: Vec::new(),
+            actor: room.actor.as_ref().map(|actor| ActorState {
+                state: actor.state,
+                schedule: actor.schedule_edited.then(|| actor.schedule.clone()),
+                schedule_block: actor.runtime.schedule_block,
+            }),
+        })
+        .collect();
+    let tmp_path = format!("{path}.tmp");
+    let history_file = std::fs::File::create(&tmp_path)?;
     let mut history_writer = std::io::BufWriter::new(history_file);
-    serde_json::to_writer(&mut history_writer, &rooms).unwrap();
+    serde_json::to_writer(&mut history_writer, &StateFile { rooms, away })?;
+    history_writer.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENSOR: &str = "A4:C1:38:00:00:01";
+
+    fn rooms() -> Vec<Room> {
+        let config: Config = toml::from_str(&format!(
+            "[[room]]\nname = \"Kitchen\"\nsensor = \"{SENSOR}\"\n\n[[room]]\nname = \"Hall\"\n"
+        ))
+        .unwrap();
+        create_rooms(&config, Vec::new(), None)
+    }
+
+    fn data(address: &str, temperature: f32) -> TPSensorData {
+        TPSensorData {
+            address: address.to_string(),
+            temperature,
+            humidity: 50,
+        }
+    }
+
+    #[test]
+    fn backfill_fills_gaps() {
+        let mut rooms = rooms();
+        let now = Utc::now();
+        let minutes = TimeDelta::minutes;
+        rooms[0].sensor_history.push(SensorHistoryItem {
+            data: data(SENSOR, 20.0),
+            timestamp: now - minutes(10),
+        });
+
+        let added = backfill_history(
+            &mut rooms,
+            vec![
+                // older than the in-memory history
+                (now - HISTORY_LEN - minutes(1), data(SENSOR, 18.0)),
+                (now - minutes(12), data(SENSOR, 19.0)),
+                // duplicates of the live reading
+                (
+                    now - minutes(10) - TimeDelta::seconds(20),
+                    data(SENSOR, 19.5),
+                ),
+                (
+                    now - minutes(10) + TimeDelta::seconds(29),
+                    data(SENSOR, 19.5),
+                ),
+                (now - minutes(9), data(SENSOR, 20.5)),
+                (now - minutes(1), data(SENSOR, 21.0)),
+                // not assigned to a room
+                (now - minutes(5), data("A4:C1:38:00:00:02", 22.0)),
+            ],
+        );
+
+        let temperatures = |samples: &[(DateTime<Utc>, TPSensorData)]| {
+            samples
+                .iter()
+                .map(|(_, d)| d.temperature)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(temperatures(&added), [19.0, 20.5, 21.0]);
+        let history: Vec<_> = rooms[0]
+            .sensor_history
+            .since(now - HISTORY_LEN)
+            .map(|item| (item.timestamp, item.data.clone()))
+            .collect();
+        assert_eq!(temperatures(&history), [19.0, 20.0, 20.5, 21.0]);
+        assert!(rooms[1].sensor_history.last().is_none());
+
+        // a second download of the same log adds nothing
+        let again = backfill_history(&mut rooms, added);
+        assert!(again.is_empty());
+    }
+
+    #[test]
+    fn lowercase_sensor_address() {
+        let config: Config = toml::from_str(&format!(
+            "[[room]]\nname = \"Kitchen\"\nsensor = \"{}\"\n",
+            SENSOR.to_lowercase()
+        ))
+        .unwrap();
+        let mut rooms = create_rooms(&config, Vec::new(), None);
+        assert_eq!(rooms[0].sensor_address, SENSOR);
+
+        let now = Utc::now();
+        let added = backfill_history(&mut rooms, vec![(now, data(SENSOR, 20.0))]);
+        assert_eq!(added.len(), 1);
+        assert_eq!(
+            rooms[0].sensor_history.last().unwrap().data,
+            data(SENSOR, 20.0)
+        );
+    }
+
+    /// Records the commands it gets.
+    struct FakeActor(Mutex<Vec<bool>>);
+
+    impl Actor for FakeActor {
+        fn describe(&self) -> String {
+            "fake".to_string()
+        }
+
+        fn set(&self, on: bool) -> futures::future::BoxFuture<'_, anyhow::Result<()>> {
+            self.0.lock().unwrap().push(on);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn set_on_for(&self, _: Duration) -> futures::future::BoxFuture<'_, anyhow::Result<()>> {
+            self.set(true)
+        }
+
+        fn state(&self) -> futures::future::BoxFuture<'_, anyhow::Result<bool>> {
+            let on = self.0.lock().unwrap().last().copied().unwrap_or_default();
+            Box::pin(async move { Ok(on) })
+        }
+    }
+
+    #[tokio::test]
+    async fn switch_off() {
+        let config: Config = toml::from_str(
+            "[[room]]\nname = \"Kitchen\"\n\
+             actor = { type = \"shelly_gen1\", url = \"http://127.0.0.1:9/relay/0\" }\n\
+             [[room]]\nname = \"Hall\"\n",
+        )
+        .unwrap();
+        let mut rooms = create_rooms(&config, Vec::new(), None);
+        let fake = Arc::new(FakeActor(Mutex::new(vec![true])));
+        rooms[0].actor.as_mut().unwrap().backend = fake.clone();
+        switch_off_actors(&Mutex::new(rooms)).await;
+        assert_eq!(*fake.0.lock().unwrap(), [true, false]);
+    }
+
+    /// Takes commands, but can't report the relay state.
+    struct BlindActor(FakeActor);
+
+    impl Actor for BlindActor {
+        fn describe(&self) -> String {
+            "blind".to_string()
+        }
+
+        fn set(&self, on: bool) -> futures::future::BoxFuture<'_, anyhow::Result<()>> {
+            self.0.set(on)
+        }
+
+        fn set_on_for(
+            &self,
+            duration: Duration,
+        ) -> futures::future::BoxFuture<'_, anyhow::Result<()>> {
+            self.0.set_on_for(duration)
+        }
+
+        fn state(&self) -> futures::future::BoxFuture<'_, anyhow::Result<bool>> {
+            Box::pin(async { Err(anyhow::anyhow!("no state topic configured")) })
+        }
+
+        fn can_read_back(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn no_read_back() {
+        let blind = BlindActor(FakeActor(Mutex::new(Vec::new())));
+        let on_until = Some(Instant::now() + Duration::from_secs(60));
+        let status = apply_actor("Kitchen", &blind, on_until, true, ActorStatus::default()).await;
+        let status = apply_actor("Kitchen", &blind, on_until, false, status).await;
+        // sent once, not repeated because the state is unknown
+        assert_eq!(*blind.0.0.lock().unwrap(), [true]);
+        assert_eq!(status.failures, 0);
+        assert!(status.last_error.is_none());
+        assert!(status.confirmed.is_none());
+    }
+
+    #[test]
+    fn reconnects() {
+        use ConnectionState::*;
+        let mut room = rooms().remove(0);
+        let count = |room: &mut Room, states: &[ConnectionState]| {
+            for state in states {
+                room.set_connection(*state);
+            }
+            room.reconnects
+        };
+        // failed attempts before the first connection
+        assert_eq!(
+            count(
+                &mut room,
+                &[Connecting, Disconnected, Connecting, Disconnected]
+            ),
+            0
+        );
+        assert_eq!(count(&mut room, &[Connecting, Connected]), 0);
+        assert_eq!(count(&mut room, &[Disconnected, Connecting]), 1);
+        // retries after a failed reconnect attempt count no more
+        assert_eq!(
+            count(&mut room, &[Disconnected, Connecting, Disconnected]),
+            1
+        );
+        assert_eq!(
+            count(
+                &mut room,
+                &[Connecting, Connected, Disconnected, Connecting]
+            ),
+            2
+        );
+        assert_eq!(room.connection, Some(Connecting));
+    }
+
+    #[test]
+    fn override_outlasts_restart() {
+        let config: Config = toml::from_str(
+            "[[room]]\nname = \"Kitchen\"\n\
+             actor = { type = \"shelly_gen1\", url = \"http://127.0.0.1:9/relay/0\" }\n\
+             schedule = [{ days = [\"mon\"], start = \"06:30\", preset = \"comfort\" }]\n",
+        )
+        .unwrap();
+        let mut rooms = create_rooms(&config, Vec::new(), None);
+        let block = schedule::active(
+            &rooms[0].actor.as_ref().unwrap().schedule,
+            Local::now().naive_local(),
+        )
+        .map(|(start, _)| start);
+        let actor = rooms[0].actor.as_mut().unwrap();
+        actor.state = HeatingState::Manual(3);
+        actor.runtime.schedule_block = block;
+
+        let path = std::env::temp_dir().join(format!("homectl-{}-state.json", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        save_state(&rooms, None, &path).unwrap();
+        let state = load_state(&path);
+        let _ = std::fs::remove_file(&path);
+
+        // the block was applied before, so the next tick keeps the state set by hand
+        let rooms = create_rooms(&config, state.rooms, None);
+        let actor = rooms[0].actor.as_ref().unwrap();
+        assert_eq!(actor.state, HeatingState::Manual(3));
+        assert!(block.is_some());
+        assert_eq!(actor.runtime.schedule_block, block);
+    }
+
+    #[test]
+    fn legacy_state_file() {
+        // written before the config file, the history database and away mode
+        let secs = Utc::now().timestamp() - 600;
+        let legacy = format!(
+            r#"[
+                {{
+                    "name": "Kitchen",
+                    "sensor_address": "{SENSOR}",
+                    "sensor": null,
+                    "sensor_history": [
+                        {{
+                            "data": {{"address": "{SENSOR}", "temperature": 20.5, "humidity": 50}},
+                            "timestamp": {{"secs_since_epoch": {secs}, "nanos_since_epoch": 500000000}}
+                        }}
+                    ],
+                    "actor": {{"address": "http://shelly.local/relay/0", "state": {{"Manual": 3}}}}
+                }},
+                {{
+                    "name": "Hall",
+                    "sensor_address": "",
+                    "sensor": null,
+                    "sensor_history": [],
+                    "actor": {{"address": "http://shelly.local/relay/1", "state": {{"Auto": 19.5}}}}
+                }}
+            ]"#
+        );
+        let path = std::env::temp_dir().join(format!("homectl-{}-legacy.json", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        std::fs::write(&path, legacy).unwrap();
+        let state = load_state(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(state.away.is_none());
+        assert_eq!(state.rooms.len(), 2);
+
+        let config: Config = toml::from_str(&format!(
+            "[[room]]\nname = \"Kitchen\"\nsensor = \"{SENSOR}\"\n\
+             actor = {{ type = \"shelly_gen1\", url = \"http://shelly.local/relay/0\" }}\n\
+             [[room]]\nname = \"Hall\"\n\
+             actor = {{ type = \"shelly_gen1\", url = \"http://shelly.local/relay/1\" }}\n"
+        ))
+        .unwrap();
+        let rooms = create_rooms(&config, state.rooms, None);
+        assert_eq!(
+            rooms[0].actor.as_ref().unwrap().state,
+            HeatingState::Manual(3)
+        );
+        assert_eq!(
+            rooms[1].actor.as_ref().unwrap().state,
+            HeatingState::Auto(19.5)
+        );
+        let item = rooms[0].sensor_history.last().unwrap();
+        assert_eq!(item.data, data(SENSOR, 20.5));
+        assert_eq!(
+            item.timestamp,
+            DateTime::from_timestamp(secs, 500_000_000).unwrap()
+        );
+    }
+
+    #[test]
+    fn sensor_status_outlives_reading() {
+        let mut rooms = rooms();
+        let now = Utc::now();
+        assert_eq!(rooms[0].sensor_status(now), SensorStatus::Reading(None));
+        assert_eq!(rooms[1].sensor_status(now), SensorStatus::None);
+
+        rooms[0].sensor_history.push(SensorHistoryItem {
+            data: data(SENSOR, 20.5),
+            timestamp: now - TimeDelta::minutes(20),
+        });
+        // `sensor` is cleared after its TTL, the age must still grow
+        rooms[0].sensor = None;
+        assert_eq!(
+            rooms[0].sensor_status(now),
+            SensorStatus::Reading(Some((20.5, Duration::from_secs(20 * 60))))
+        );
+    }
 }
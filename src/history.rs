@@ -0,0 +1,321 @@
+//! Bounded in-memory sensor history, ordered by time.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::VecDeque;
+
+use crate::data::SensorHistoryItem;
+
+/// Minimum distance between kept live samples
+const MIN_SPACING: TimeDelta = TimeDelta::seconds(10);
+
+/// Upper bound of samples kept, one per `MIN_SPACING` for a day
+const CAPACITY: usize = 24 * 60 * 6;
+
+/// A downsampled point: mean temperature of a time bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    pub timestamp: DateTime<Utc>,
+    pub temperature: f32,
+}
+
+struct Downsampled {
+    span: TimeDelta,
+    points: usize,
+    /// Start of the oldest bucket
+    start: DateTime<Utc>,
+    /// End of the newest bucket
+    end: DateTime<Utc>,
+    generation: u64,
+    result: Vec<Point>,
+}
+
+pub struct History {
+    items: VecDeque<SensorHistoryItem>,
+    max_age: TimeDelta,
+    /// Incremented on every change
+    generation: u64,
+    cache: Option<Downsampled>,
+}
+
+impl History {
+    pub fn new(max_age: TimeDelta) -> Self {
+        Self {
+            items: VecDeque::new(),
+            max_age,
+            generation: 0,
+            cache: None,
+        }
+    }
+
+    pub fn last(&self) -> Option<&SensorHistoryItem> {
+        self.items.back()
+    }
+
+    /// Samples taken at or after `since`, oldest first.
+    pub fn since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> impl DoubleEndedIterator<Item = &SensorHistoryItem> {
+        let start = self.items.partition_point(|item| item.timestamp < since);
+        self.items.range(start..)
+    }
+
+    /// Adds a sample, keeping the history ordered by time.
+    pub fn push(&mut self, item: SensorHistoryItem) {
+        // the newest sample is replaced until it is `MIN_SPACING` after the one before
+        let len = self.items.len();
+        if len >= 2
+            && self.items[len - 2].timestamp + MIN_SPACING > self.items[len - 1].timestamp
+            && self.items[len - 1].timestamp <= item.timestamp
+        {
+            self.items[len - 1] = item;
+            self.generation += 1;
+            return;
+        }
+        let idx = self
+            .items
+            .partition_point(|i| i.timestamp <= item.timestamp);
+        self.items.insert(idx, item);
+        self.trim();
+    }
+
+    /// Adds a sample unless there already is one within `resolution` of it.
+    /// Returns whether it was added.
+    pub fn insert_if_missing(&mut self, item: SensorHistoryItem, resolution: TimeDelta) -> bool {
+        let idx = self.items.partition_point(|i| i.timestamp < item.timestamp);
+        let is_duplicate = |other: &SensorHistoryItem| {
+            other.timestamp.max(item.timestamp) - other.timestamp.min(item.timestamp) < resolution
+        };
+        if self.items.get(idx).is_some_and(is_duplicate)
+            || idx > 0 && is_duplicate(&self.items[idx - 1])
+        {
+            return false;
+        }
+        self.items.insert(idx, item);
+        self.trim();
+        true
+    }
+
+    /// Drops samples that are too old or exceed the capacity.
+    fn trim(&mut self) {
+        let start = Utc::now() - self.max_age;
+        while self
+            .items
+            .front()
+            .is_some_and(|item| item.timestamp < start)
+        {
+            self.items.pop_front();
+        }
+        while self.items.len() > CAPACITY {
+            self.items.pop_front();
+        }
+        self.generation += 1;
+    }
+
+    /// Returns up to `points + 1` averaged points covering at least the `span`
+    /// before `now`, oldest first. Buckets without samples are left out.
+    pub fn downsample(&mut self, now: DateTime<Utc>, span: TimeDelta, points: usize) -> &[Point] {
+        let points = points.max(1);
+        let bucket = (span.num_milliseconds() as u64)
+            .div_ceil(points as u64)
+            .max(1) as i64;
+        // buckets are aligned to the epoch, so the result only changes when
+        // the data does or a new bucket starts
+        let align = |t: DateTime<Utc>| t.timestamp_millis().div_euclid(bucket) * bucket;
+        let start = DateTime::from_timestamp_millis(align(now - span)).unwrap_or(now - span);
+        let end = DateTime::from_timestamp_millis(align(now) + bucket).unwrap_or(now);
+        let bucket = TimeDelta::milliseconds(bucket);
+
+        let cached = self.cache.as_ref().is_some_and(|c| {
+            c.span == span
+                && c.points == points
+                && c.start == start
+                && c.end == end
+                && c.generation == self.generation
+        });
+        if !cached {
+            let mean = |idx: usize, sum: f32, count: u32| Point {
+                timestamp: start + bucket * idx as i32 + bucket / 2,
+                temperature: sum / count as f32,
+            };
+            let mut result = Vec::with_capacity(points + 1);
+            // bucket index, temperature sum and sample count of the current bucket
+            let mut current: Option<(usize, f32, u32)> = None;
+            for item in self.since(start).take_while(|item| item.timestamp < end) {
+                let idx = ((item.timestamp - start).num_milliseconds() / bucket.num_milliseconds())
+                    as usize;
+                if let Some((current_idx, sum, count)) = current
+                    && current_idx != idx
+                {
+                    result.push(mean(current_idx, sum, count));
+                    current = None;
+                }
+                let (_, sum, count) = current.get_or_insert((idx, 0.0, 0));
+                *sum += item.data.temperature;
+                *count += 1;
+            }
+            if let Some((idx, sum, count)) = current {
+                result.push(mean(idx, sum, count));
+            }
+            self.cache = Some(Downsampled {
+                span,
+                points,
+                start,
+                end,
+                generation: self.generation,
+                result,
+            });
+        }
+        self.cache.as_ref().map_or(&[], |c| &c.result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::TPSensorData;
+
+    fn item(timestamp: DateTime<Utc>, temperature: f32) -> SensorHistoryItem {
+        SensorHistoryItem {
+            data: TPSensorData {
+                address: "A4:C1:38:00:00:01".to_string(),
+                temperature,
+                humidity: 50,
+            },
+            timestamp,
+        }
+    }
+
+    /// Current time rounded down to the minute
+    fn minute() -> DateTime<Utc> {
+        let now = Utc::now().timestamp();
+        DateTime::from_timestamp(now - now.rem_euclid(60), 0).unwrap()
+    }
+
+    fn seconds(history: &History, base: DateTime<Utc>) -> Vec<i64> {
+        history
+            .since(base - TimeDelta::days(2))
+            .map(|item| (item.timestamp - base).num_seconds())
+            .collect()
+    }
+
+    #[test]
+    fn min_spacing() {
+        let base = minute() - TimeDelta::hours(1);
+        let mut history = History::new(TimeDelta::hours(24));
+        for s in [0, 5, 8, 12, 15, 30] {
+            history.push(item(base + TimeDelta::seconds(s), s as f32));
+        }
+        // the newest sample is replaced while it is closer than MIN_SPACING
+        // to the one before
+        assert_eq!(seconds(&history, base), [0, 12, 30]);
+        assert_eq!(history.last().unwrap().data.temperature, 30.0);
+
+        // older samples are inserted in order instead of replacing the newest
+        history.push(item(base + TimeDelta::seconds(20), 20.0));
+        history.push(item(base + TimeDelta::seconds(31), 31.0));
+        assert_eq!(seconds(&history, base), [0, 12, 20, 30, 31]);
+    }
+
+    #[test]
+    fn capacity_and_age() {
+        let base = minute() - TimeDelta::hours(36);
+        let mut history = History::new(TimeDelta::hours(48));
+        let total = CAPACITY as i64 + 5;
+        for i in 0..total {
+            history.push(item(base + MIN_SPACING * i as i32, 20.0));
+        }
+        let kept = seconds(&history, base);
+        assert_eq!(kept.len(), CAPACITY);
+        assert_eq!(kept[0], 5 * MIN_SPACING.num_seconds());
+        assert_eq!(
+            *kept.last().unwrap(),
+            (total - 1) * MIN_SPACING.num_seconds()
+        );
+
+        let mut history = History::new(TimeDelta::hours(1));
+        history.push(item(minute() - TimeDelta::minutes(61), 20.0));
+        history.push(item(minute() - TimeDelta::minutes(59), 20.0));
+        history.insert_if_missing(item(minute() - TimeDelta::minutes(90), 20.0), MIN_SPACING);
+        assert_eq!(history.since(DateTime::UNIX_EPOCH).count(), 1);
+    }
+
+    #[test]
+    fn insert_if_missing() {
+        let base = minute() - TimeDelta::hours(1);
+        let mut history = History::new(TimeDelta::hours(24));
+        let resolution = TimeDelta::seconds(30);
+        history.push(item(base, 20.0));
+        history.push(item(base + TimeDelta::seconds(60), 20.0));
+        assert!(!history.insert_if_missing(item(base + TimeDelta::seconds(29), 0.0), resolution));
+        assert!(!history.insert_if_missing(item(base + TimeDelta::seconds(31), 0.0), resolution));
+        assert!(history.insert_if_missing(item(base + TimeDelta::seconds(30), 0.0), resolution));
+        assert!(history.insert_if_missing(item(base - TimeDelta::seconds(30), 0.0), resolution));
+        assert_eq!(seconds(&history, base), [-30, 0, 30, 60]);
+    }
+
+    #[test]
+    fn downsample() {
+        // a fixed time right after an even hour, so the two hour bucket
+        // below starts before the span
+        let base = DateTime::parse_from_rfc3339("2025-01-06T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let now = base + TimeDelta::seconds(1);
+        // keep the samples, they are older than a day
+        let mut history = History::new(Utc::now() - base + TimeDelta::days(1));
+        for (s, temperature) in [(-3660, 10.0), (-120, 20.0), (-100, 22.0), (0, 24.0)] {
+            history.push(item(base + TimeDelta::seconds(s), temperature));
+        }
+        // one minute buckets, from the one `now - span` is in to the one `now` is in
+        let points = |history: &mut History, span, count| {
+            history
+                .downsample(now, span, count)
+                .iter()
+                .map(|p| ((p.timestamp - base).num_seconds(), p.temperature))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            points(&mut history, TimeDelta::hours(1), 60),
+            [(-90, 21.0), (30, 24.0)]
+        );
+
+        // changes invalidate the cached result
+        history.push(item(base + TimeDelta::seconds(-80), 24.0));
+        assert_eq!(
+            points(&mut history, TimeDelta::hours(1), 60),
+            [(-90, 22.0), (30, 24.0)]
+        );
+        history.push(item(base + TimeDelta::seconds(-40), 30.0));
+        assert_eq!(
+            points(&mut history, TimeDelta::hours(1), 60),
+            [(-90, 22.0), (-30, 30.0), (30, 24.0)]
+        );
+        assert!(history.insert_if_missing(item(base - TimeDelta::minutes(30), 16.0), MIN_SPACING));
+        assert_eq!(
+            points(&mut history, TimeDelta::hours(1), 60),
+            [(-1770, 16.0), (-90, 22.0), (-30, 30.0), (30, 24.0)]
+        );
+        // as do other parameters
+        assert_eq!(
+            points(&mut history, TimeDelta::minutes(2), 2),
+            [(-90, 22.0), (-30, 30.0), (30, 24.0)]
+        );
+
+        // no points is treated as one bucket as long as the span, the window
+        // still covers the whole span
+        let all = points(&mut history, TimeDelta::hours(2), 0);
+        assert_eq!(all.len(), 2, "{all:?}");
+        let mean = (10.0 + 16.0 + 20.0 + 22.0 + 24.0 + 30.0) / 6.0;
+        assert_eq!(all[0].0, -3600);
+        assert!((all[0].1 - mean).abs() < 1e-4, "{all:?}");
+        assert_eq!(all[1], (3600, 24.0));
+
+        // buckets are at least a millisecond long
+        let short = points(&mut history, TimeDelta::milliseconds(5), 10);
+        assert_eq!(short, []);
+        history.push(item(now, 25.0));
+        let short = points(&mut history, TimeDelta::milliseconds(5), 10);
+        assert_eq!(short, [(1, 25.0)]);
+    }
+}
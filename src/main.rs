@@ -1,10 +1,64 @@
+use clap::Parser;
 use eframe::egui;
+use std::process::ExitCode;
 
+mod actor;
+mod api;
 mod bt;
+mod bthome;
+mod cli;
+mod config;
+mod control;
+mod daemon;
 mod data;
+mod history;
+mod metrics;
+mod mqtt;
+mod safety;
+mod schedule;
+mod sensors;
+mod store;
 mod ui;
 
-fn main() {
+fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
+    let config = match config::load_config(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    env_logger::init();
+
+    let result = match cli.command.unwrap_or(cli::Command::Run(cli.run)) {
+        cli::Command::Run(args) => run(config, args),
+        cli::Command::Scan { seconds } => cli::scan(&config, seconds),
+        cli::Command::Read { address, timeout } => cli::read(&config, address, timeout),
+        cli::Command::Rooms { json } => cli::rooms(&config, json),
+        cli::Command::Set { room, mode, value } => cli::set(&config, &room, mode, &value),
+        cli::Command::Export {
+            room,
+            from,
+            to,
+            period,
+        } => cli::export(&config, room.as_deref(), from, to, period),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(config: config::Config, args: cli::RunArgs) -> anyhow::Result<()> {
+    if args.headless {
+        daemon::Daemon::start(&config, None, args.discovery()).wait();
+        return Ok(());
+    }
+
     // Run the GUI in the main thread.
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
@@ -15,7 +69,13 @@ fn main() {
     eframe::run_native(
         "My egui App",
         options,
-        Box::new(|cc| Ok(Box::<ui::MyApp>::new(ui::MyApp::new(cc)))),
+        Box::new(|cc| {
+            Ok(Box::<ui::MyApp>::new(ui::MyApp::new(
+                cc,
+                config,
+                args.discovery(),
+            )))
+        }),
     )
-    .expect("Failed to start gui");
+    .map_err(|e| anyhow::anyhow!("Failed to start gui: {e}"))
 }
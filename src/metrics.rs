@@ -0,0 +1,265 @@
+//! Room readings and heating state in the Prometheus text exposition format.
+
+use chrono::Utc;
+use std::fmt::Write;
+
+use crate::data::{HeatingState, Room};
+
+/// One metric family with a sample per room.
+struct Family<'a> {
+    name: &'a str,
+    help: &'a str,
+    kind: &'a str,
+    samples: Vec<(&'a str, f64)>,
+}
+
+impl<'a> Family<'a> {
+    fn new(name: &'a str, kind: &'a str, help: &'a str) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            samples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, room: &'a str, value: Option<f64>) {
+        if let Some(value) = value {
+            self.samples.push((room, value));
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (room, value) in &self.samples {
+            let _ = writeln!(out, "{}{{room=\"{}\"}} {value}", self.name, escape(room));
+        }
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn render(rooms: &[Room]) -> String {
+    let mut temperature = Family::new(
+        "homectl_temperature_celsius",
+        "gauge",
+        "Latest temperature reported by the room's sensor.",
+    );
+    let mut humidity = Family::new(
+        "homectl_humidity_percent",
+        "gauge",
+        "Latest relative humidity reported by the room's sensor.",
+    );
+    let mut age = Family::new(
+        "homectl_sensor_age_seconds",
+        "gauge",
+        "Time since the room's sensor last reported.",
+    );
+    let mut battery = Family::new(
+        "homectl_sensor_battery_percent",
+        "gauge",
+        "Battery level of the room's sensor.",
+    );
+    let mut rssi = Family::new(
+        "homectl_sensor_rssi_dbm",
+        "gauge",
+        "Signal strength of the room's sensor.",
+    );
+    let mut reconnects = Family::new(
+        "homectl_sensor_reconnects_total",
+        "counter",
+        "Times the connection to the room's sensor was set up again after it dropped.",
+    );
+    let mut target = Family::new(
+        "homectl_heating_target_celsius",
+        "gauge",
+        "Target temperature of rooms in auto mode.",
+    );
+    let mut level = Family::new(
+        "homectl_heating_level",
+        "gauge",
+        "Power level 0-6 of rooms in manual mode.",
+    );
+    let mut relay = Family::new(
+        "homectl_relay_on",
+        "gauge",
+        "Whether the heating relay was last read back as on.",
+    );
+    let mut on_time = Family::new(
+        "homectl_relay_on_seconds_total",
+        "counter",
+        "Time the heating relay was confirmed on.",
+    );
+    let mut failures = Family::new(
+        "homectl_actor_failures_total",
+        "counter",
+        "Relay commands and read-backs that failed.",
+    );
+
+    let now = Utc::now();
+    for room in rooms {
+        let name = room.name.as_str();
+        if let Some(sensor) = &room.sensor {
+            temperature.add(name, Some(sensor.temperature as f64));
+            humidity.add(name, Some(sensor.humidity as f64));
+        }
+        if !room.sensor_address.is_empty() {
+            age.add(
+                name,
+                room.sensor_history
+                    .last()
+                    .map(|item| (now - item.timestamp).num_milliseconds() as f64 / 1000.0),
+            );
+            battery.add(name, room.battery.map(f64::from));
+            rssi.add(name, room.rssi.map(f64::from));
+            reconnects.add(name, Some(room.reconnects as f64));
+        }
+        if let Some(actor) = &room.actor {
+            match actor.state {
+                HeatingState::Auto(t) => target.add(name, Some(t as f64)),
+                HeatingState::Manual(l) => level.add(name, Some(l as f64)),
+            }
+            relay.add(name, actor.status.confirmed.map(|(on, _)| on as u8 as f64));
+            on_time.add(name, Some(actor.status.on_time.as_secs_f64()));
+            failures.add(name, Some(actor.status.failures as f64));
+        }
+    }
+
+    let mut out = String::new();
+    for family in [
+        temperature,
+        humidity,
+        age,
+        battery,
+        rssi,
+        reconnects,
+        target,
+        level,
+        relay,
+        on_time,
+        failures,
+    ] {
+        family.write(&mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::data::{SensorHistoryItem, TPSensorData, create_rooms};
+
+    #[test]
+    fn families() {
+        let config: Config = toml::from_str(
+            r#"
+            [[room]]
+            name = 'Kitchen "east"'
+            sensor = "A4:C1:38:00:00:01"
+
+            [[room]]
+            name = 'Hall\Attic'
+            sensor = "A4:C1:38:00:00:02"
+            actor = { type = "gpio", pin = 17, state = { Manual = 3 } }
+
+            [[room]]
+            name = "Bath"
+            actor = { type = "gpio", pin = 18, state = { Auto = 21.5 } }
+            "#,
+        )
+        .unwrap();
+        let mut rooms = create_rooms(&config, Vec::new(), None);
+        let data = TPSensorData {
+            address: "A4:C1:38:00:00:01".to_string(),
+            temperature: 20.5,
+            humidity: 45,
+        };
+        rooms[0].sensor = Some(data.clone());
+        rooms[0].sensor_history.push(SensorHistoryItem {
+            data,
+            timestamp: Utc::now(),
+        });
+        rooms[0].battery = Some(80);
+        rooms[0].reconnects = 2;
+
+        let out = render(&rooms);
+        let lines: Vec<_> = out.lines().collect();
+        for (name, kind) in [
+            ("homectl_temperature_celsius", "gauge"),
+            ("homectl_humidity_percent", "gauge"),
+            ("homectl_sensor_age_seconds", "gauge"),
+            ("homectl_sensor_battery_percent", "gauge"),
+            ("homectl_sensor_rssi_dbm", "gauge"),
+            ("homectl_sensor_reconnects_total", "counter"),
+            ("homectl_heating_target_celsius", "gauge"),
+            ("homectl_heating_level", "gauge"),
+            ("homectl_relay_on", "gauge"),
+            ("homectl_relay_on_seconds_total", "counter"),
+            ("homectl_actor_failures_total", "counter"),
+        ] {
+            assert!(
+                lines.contains(&format!("# TYPE {name} {kind}").as_str()),
+                "{name}"
+            );
+            assert!(
+                lines
+                    .iter()
+                    .any(|l| l.starts_with(&format!("# HELP {name} "))),
+                "{name}"
+            );
+        }
+
+        let samples = |name: &str| {
+            lines
+                .iter()
+                .filter_map(|l| l.strip_prefix(name)?.strip_prefix('{'))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            samples("homectl_temperature_celsius"),
+            [r#"room="Kitchen \"east\""} 20.5"#]
+        );
+        assert_eq!(
+            samples("homectl_humidity_percent"),
+            [r#"room="Kitchen \"east\""} 45"#]
+        );
+        assert_eq!(
+            samples("homectl_sensor_battery_percent"),
+            [r#"room="Kitchen \"east\""} 80"#]
+        );
+        // gauges are left out without a reading, counters start at 0
+        assert_eq!(samples("homectl_sensor_age_seconds").len(), 1);
+        assert_eq!(samples("homectl_sensor_rssi_dbm"), Vec::<&str>::new());
+        assert_eq!(
+            samples("homectl_sensor_reconnects_total"),
+            [r#"room="Kitchen \"east\""} 2"#, r#"room="Hall\\Attic"} 0"#]
+        );
+        assert_eq!(
+            samples("homectl_heating_level"),
+            [r#"room="Hall\\Attic"} 3"#]
+        );
+        assert_eq!(
+            samples("homectl_heating_target_celsius"),
+            [r#"room="Bath"} 21.5"#]
+        );
+        assert_eq!(samples("homectl_relay_on"), Vec::<&str>::new());
+        assert_eq!(
+            samples("homectl_actor_failures_total"),
+            [r#"room="Hall\\Attic"} 0"#, r#"room="Bath"} 0"#]
+        );
+    }
+
+    #[test]
+    fn label_escaping() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+    }
+}
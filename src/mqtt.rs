@@ -0,0 +1,539 @@
+//! Publishes sensor readings and heating states to an MQTT broker. All
+//! values are retained, so new subscribers get the latest ones right away.
+//!
+//! - `<prefix>/status`: `online`, or `offline` once the connection is lost
+//! - `<prefix>/<room>/reading`: latest sensor reading as JSON
+//! - `<prefix>/<room>/heating`: heating state as JSON, e.g. `{"Auto":21.0}`
+//! - `<prefix>/<room>/climate`: heating state as set by the user, in terms of
+//!   a Home Assistant climate entity
+//!
+//! Rooms are controlled by publishing to `<prefix>/<room>/set/mode` (`off`,
+//! `heat` or `auto`), `.../set/temperature` or `.../set/preset` (`level 1`
+//! to `level 6`). With `discovery` enabled, every room is announced to Home
+//! Assistant as a temperature and humidity sensor and a climate entity.
+//! Entities a room no longer has are removed again.
+
+use rumqttc::{AsyncClient, Event, Incoming, LastWill, MqttOptions, Publish, QoS};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::config::validate_heating_state;
+use crate::data::{HeatingState, Room, TPSensorData};
+use crate::schedule::Presets;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic all values are published under
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Announce the rooms to Home Assistant
+    #[serde(default)]
+    pub discovery: bool,
+    /// Topic prefix Home Assistant watches for discovery messages
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_prefix() -> String {
+    "homectl".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+impl MqttConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.host.is_empty() {
+            Err("host must not be empty".to_string())
+        } else if self.username.is_some() != self.password.is_some() {
+            Err("needs both username and password".to_string())
+        } else if let Some(prefix) = [&self.prefix, &self.discovery_prefix]
+            .into_iter()
+            .find(|prefix| prefix.is_empty() || prefix.contains(['+', '#']))
+        {
+            Err(format!("'{prefix}' is not a valid topic prefix"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Number of messages queued while the broker is unreachable
+const QUEUE_LEN: usize = 100;
+
+#[derive(serde::Serialize)]
+struct Reading<'a> {
+    sensor: &'a str,
+    temperature: f32,
+    humidity: u8,
+    timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// Handle to publish values. Cheap to clone, publishing never blocks.
+#[derive(Clone)]
+pub struct Publisher {
+    client: AsyncClient,
+    prefix: String,
+    /// Topic prefix for Home Assistant discovery, if enabled
+    discovery_prefix: Option<String>,
+}
+
+impl Publisher {
+    /// Creates the client and keeps it connected in a background task, which
+    /// also applies commands to `rooms` and notifies `changed` when one
+    /// changed a heating state. Must be called from within the tokio runtime.
+    pub fn connect(
+        config: &MqttConfig,
+        rooms: Arc<Mutex<Vec<Room>>>,
+        presets: Presets,
+        changed: Arc<Notify>,
+    ) -> Self {
+        let status = format!("{}/status", config.prefix);
+        let mut options = MqttOptions::new(
+            format!("homectl-{}", std::process::id()),
+            &config.host,
+            config.port,
+        );
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(&status, "offline", QoS::AtLeastOnce, true));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, QUEUE_LEN);
+        let publisher = Self {
+            client,
+            prefix: config.prefix.clone(),
+            discovery_prefix: config.discovery.then(|| config.discovery_prefix.clone()),
+        };
+
+        let this = publisher.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        println!("MQTT: connected to {}:{}", config.host, config.port);
+                        let _ = this
+                            .client
+                            .try_publish(&status, QoS::AtLeastOnce, true, "online");
+                        let commands = format!("{}/+/set/+", config.prefix);
+                        let _ = this.client.try_subscribe(commands, QoS::AtLeastOnce);
+                        for room in rooms.lock().unwrap().iter() {
+                            this.announce_room(room);
+                            // the broker may have lost the retained states
+                            let Some(actor) = &room.actor else { continue };
+                            if let Some(state) = actor.runtime.published {
+                                this.climate(&room.name, state);
+                            }
+                            if let Some(state) = actor.runtime.applied {
+                                this.heating(&room.name, state);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                        if this.command(&publish, &rooms, &presets) {
+                            changed.notify_one();
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        eprintln!("MQTT: {e}");
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+
+        publisher
+    }
+
+    fn topic(&self, room: &str, name: &str) -> String {
+        format!("{}/{}/{name}", self.prefix, topic_name(room))
+    }
+
+    fn publish(&self, room: &str, name: &str, payload: Vec<u8>) {
+        let topic = self.topic(room, name);
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+        {
+            eprintln!("MQTT: dropped message for {topic}: {e}");
+        }
+    }
+
+    pub fn reading(&self, room: &str, data: &TPSensorData) {
+        let reading = Reading {
+            sensor: &data.address,
+            temperature: data.temperature,
+            humidity: data.humidity,
+            timestamp: chrono::Utc::now(),
+        };
+        if let Ok(payload) = serde_json::to_vec(&reading) {
+            self.publish(room, "reading", payload);
+        }
+    }
+
+    pub fn heating(&self, room: &str, state: HeatingState) {
+        if let Ok(payload) = serde_json::to_vec(&state) {
+            self.publish(room, "heating", payload);
+        }
+    }
+
+    /// Publishes the heating state as set by the user. This is what Home
+    /// Assistant shows and changes, unlike the state actually driven.
+    pub fn climate(&self, room: &str, state: HeatingState) {
+        let (mode, preset, target) = match state {
+            HeatingState::Manual(0) => ("off", "none".to_string(), None),
+            HeatingState::Manual(level) => ("heat", format!("level {level}"), None),
+            HeatingState::Auto(target) => ("auto", "none".to_string(), Some(target)),
+        };
+        let payload = json!({ "mode": mode, "preset": preset, "target": target });
+        self.publish(room, "climate", payload.to_string().into_bytes());
+    }
+
+    /// Publishes the Home Assistant discovery messages of a room, if
+    /// discovery is enabled. Entities the room doesn't have are removed, in
+    /// case it had them with an earlier config.
+    pub fn announce_room(&self, room: &Room) {
+        let Some(discovery_prefix) = &self.discovery_prefix else {
+            return;
+        };
+        let availability = format!("{}/status", self.prefix);
+        let id = format!("homectl_{}", object_id(&room.name));
+        let device = json!({
+            "identifiers": [id],
+            "name": room.name,
+            "manufacturer": "homectl",
+        });
+        // an empty config removes the entity
+        let mut entities = Vec::new();
+        for (quantity, unit) in [("temperature", "°C"), ("humidity", "%")] {
+            let topic = format!("{discovery_prefix}/sensor/{id}_{quantity}/config");
+            if room.sensor_address.is_empty() {
+                entities.push((topic, String::new()));
+                continue;
+            }
+            let entity = json!({
+                "name": quantity[..1].to_uppercase() + &quantity[1..],
+                "unique_id": format!("{id}_{quantity}"),
+                "device": device,
+                "device_class": quantity,
+                "state_class": "measurement",
+                "unit_of_measurement": unit,
+                "state_topic": self.topic(&room.name, "reading"),
+                "value_template": format!("{{{{ value_json.{quantity} }}}}"),
+                "availability_topic": availability,
+            });
+            entities.push((topic, entity.to_string()));
+        }
+        let topic = format!("{discovery_prefix}/climate/{id}/config");
+        if room.actor.is_none() {
+            entities.push((topic, String::new()));
+        } else {
+            let climate = self.topic(&room.name, "climate");
+            let mut entity = json!({
+                "name": null,
+                "unique_id": format!("{id}_climate"),
+                "device": device,
+                "modes": ["off", "heat", "auto"],
+                "mode_state_topic": climate,
+                "mode_state_template": "{{ value_json.mode }}",
+                "mode_command_topic": self.topic(&room.name, "set/mode"),
+                "preset_modes": (1..=6).map(|level| format!("level {level}")).collect::<Vec<_>>(),
+                "preset_mode_state_topic": climate,
+                "preset_mode_value_template": "{{ value_json.preset }}",
+                "preset_mode_command_topic": self.topic(&room.name, "set/preset"),
+                "temperature_state_topic": climate,
+                "temperature_state_template": "{{ value_json.target }}",
+                "temperature_command_topic": self.topic(&room.name, "set/temperature"),
+                "min_temp": 5,
+                "max_temp": 30,
+                "temp_step": 0.5,
+                "temperature_unit": "C",
+                "availability_topic": availability,
+            });
+            if !room.sensor_address.is_empty() {
+                entity["current_temperature_topic"] = self.topic(&room.name, "reading").into();
+                entity["current_temperature_template"] = "{{ value_json.temperature }}".into();
+            }
+            entities.push((topic, entity.to_string()));
+        }
+        for (topic, payload) in entities {
+            if let Err(e) = self
+                .client
+                .try_publish(&topic, QoS::AtLeastOnce, true, payload)
+            {
+                eprintln!("MQTT: dropped message for {topic}: {e}");
+            }
+        }
+    }
+
+    /// Applies a command published to `<prefix>/<room>/set/<command>`.
+    /// Returns whether it changed the heating state.
+    fn command(&self, publish: &Publish, rooms: &Mutex<Vec<Room>>, presets: &Presets) -> bool {
+        let Some(rest) = publish.topic.strip_prefix(&format!("{}/", self.prefix)) else {
+            return false;
+        };
+        let Some((room_name, command)) = rest.split_once("/set/") else {
+            return false;
+        };
+        let payload = String::from_utf8_lossy(&publish.payload);
+        let payload = payload.trim();
+
+        let mut rooms = rooms.lock().unwrap();
+        let Some(room) = rooms.iter_mut().find(|r| topic_name(&r.name) == room_name) else {
+            eprintln!("MQTT: command for unknown room '{room_name}'");
+            return false;
+        };
+        let Some(actor) = &mut room.actor else {
+            eprintln!("MQTT: {} has no actor", room.name);
+            return false;
+        };
+        let state = match (command, payload) {
+            ("mode", "off") => Some(HeatingState::Manual(0)),
+            ("mode", "heat") => match actor.state {
+                HeatingState::Manual(level) if level > 0 => Some(actor.state),
+                _ => Some(HeatingState::Manual(3)),
+            },
+            ("mode", "auto") => match actor.state {
+                HeatingState::Auto(_) => Some(actor.state),
+                _ => Some(HeatingState::Auto(presets.comfort)),
+            },
+            ("temperature", target) => target.parse().ok().map(HeatingState::Auto),
+            ("preset", "none") => Some(actor.state),
+            ("preset", preset) => preset
+                .strip_prefix("level ")
+                .and_then(|level| level.parse().ok())
+                .map(HeatingState::Manual),
+            _ => None,
+        };
+        match state.map(|state| validate_heating_state(&state).map(|_| state)) {
+            Some(Ok(state)) if state != actor.state => {
+                println!("{}: heating state set to {state:?} via MQTT", room.name);
+                actor.state = state;
+                return true;
+            }
+            Some(Ok(_)) => return false,
+            Some(Err(e)) => eprintln!("MQTT: {}: {e}", room.name),
+            None => eprintln!("MQTT: {}: invalid command {command} '{payload}'", room.name),
+        }
+        // Home Assistant shows the requested state until it is told otherwise
+        self.climate(&room.name, actor.state);
+        false
+    }
+}
+
+/// Room name as used in topics. Wildcards and separators would break the
+/// topic structure.
+fn topic_name(room: &str) -> String {
+    room.replace(['/', '+', '#'], "_")
+}
+
+/// Room name as used in Home Assistant ids, which allow `[a-zA-Z0-9_-]` only.
+fn object_id(room: &str) -> String {
+    room.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::EventLoop;
+    use std::collections::HashMap;
+
+    /// Topic and payload of received messages, with their retain flag
+    type Received = HashMap<String, (String, bool)>;
+
+    fn subscribe(config: &MqttConfig, id: &str) -> (AsyncClient, EventLoop) {
+        let options = MqttOptions::new(id, &config.host, config.port);
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        client
+            .try_subscribe(format!("{}/#", config.prefix), QoS::AtLeastOnce)
+            .unwrap();
+        (client, eventloop)
+    }
+
+    /// Polls until `done` returns true for the messages received so far.
+    async fn receive(
+        eventloop: &mut EventLoop,
+        received: &mut Received,
+        done: impl Fn(&Received) -> bool,
+    ) {
+        let wait = async {
+            while !done(received) {
+                let event = eventloop.poll().await.unwrap();
+                if let Event::Incoming(Incoming::Publish(publish)) = event {
+                    let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                    received.insert(publish.topic, (payload, publish.retain));
+                }
+            }
+        };
+        if tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .is_err()
+        {
+            panic!("timeout, received {received:?}");
+        }
+    }
+
+    /// Needs a broker, e.g. `HOMECTL_TEST_MQTT=localhost:1883 cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn broker() {
+        let broker = std::env::var("HOMECTL_TEST_MQTT").expect("HOMECTL_TEST_MQTT=<host>:<port>");
+        let (host, port) = broker
+            .rsplit_once(':')
+            .expect("HOMECTL_TEST_MQTT=<host>:<port>");
+        let config = MqttConfig {
+            host: host.to_string(),
+            port: port.parse().unwrap(),
+            username: None,
+            password: None,
+            prefix: format!("homectl-test-{}", std::process::id()),
+            discovery: true,
+            discovery_prefix: format!("homectl-test-{}/discovery", std::process::id()),
+        };
+        let topic = |name: &str| format!("{}/{name}", config.prefix);
+        let room_config = "[[room]]\nname = \"Kitchen\"\nsensor = \"A4:C1:38:00:00:01\"\n\
+            actor = { type = \"shelly_gen1\", url = \"http://127.0.0.1:9/relay/0\" }\n";
+        let room_config = toml::from_str(room_config).unwrap();
+        let rooms = crate::data::create_rooms(&room_config, Vec::new(), None);
+        let rooms = Arc::new(Mutex::new(rooms));
+        let changed = Arc::new(Notify::new());
+
+        // on a runtime of its own, so that its connection can be cut
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let publisher = {
+            let _guard = rt.enter();
+            Publisher::connect(&config, rooms.clone(), Presets::default(), changed.clone())
+        };
+        let data = TPSensorData {
+            address: "A4:C1:38:00:00:01".to_string(),
+            temperature: 21.5,
+            humidity: 45,
+        };
+        publisher.reading("Kitchen", &data);
+        publisher.heating("Kitchen", HeatingState::Auto(20.5));
+        publisher.climate("Kitchen", HeatingState::Manual(3));
+
+        let mut received = Received::new();
+        let expected = [
+            topic("status"),
+            topic("Kitchen/reading"),
+            topic("Kitchen/heating"),
+            topic("Kitchen/climate"),
+            topic("discovery/sensor/homectl_kitchen_temperature/config"),
+        ];
+        let (client, mut eventloop) = subscribe(&config, "homectl-test-live");
+        let complete = |r: &Received| expected.iter().all(|t| r.contains_key(t));
+        receive(&mut eventloop, &mut received, complete).await;
+
+        // a late subscriber gets the retained values
+        let (_late, mut late_eventloop) = subscribe(&config, "homectl-test-late");
+        let mut retained = Received::new();
+        receive(&mut late_eventloop, &mut retained, complete).await;
+        assert_eq!(retained[&topic("status")], ("online".to_string(), true));
+        let (reading, is_retained) = &retained[&topic("Kitchen/reading")];
+        assert!(is_retained);
+        let reading: serde_json::Value = serde_json::from_str(reading).unwrap();
+        assert_eq!(reading["sensor"], "A4:C1:38:00:00:01");
+        assert_eq!(reading["temperature"], 21.5);
+        assert_eq!(reading["humidity"], 45);
+        assert!(reading["timestamp"].is_string());
+        assert_eq!(
+            retained[&topic("Kitchen/heating")],
+            (r#"{"Auto":20.5}"#.to_string(), true)
+        );
+        let (climate, is_retained) = &retained[&topic("Kitchen/climate")];
+        assert!(is_retained);
+        let climate: serde_json::Value = serde_json::from_str(climate).unwrap();
+        assert_eq!(
+            climate,
+            json!({"mode": "heat", "preset": "level 3", "target": null})
+        );
+        let entity = topic("discovery/sensor/homectl_kitchen_temperature/config");
+        let (entity, is_retained) = &retained[&entity];
+        assert!(is_retained);
+        let entity: serde_json::Value = serde_json::from_str(entity).unwrap();
+        assert_eq!(entity["state_topic"], topic("Kitchen/reading"));
+        assert_eq!(entity["availability_topic"], topic("status"));
+
+        // commands change the heating state and ask for it to be saved
+        let set = topic("Kitchen/set/temperature");
+        client
+            .publish(&set, QoS::AtLeastOnce, false, "21.5")
+            .await
+            .unwrap();
+        let notified = async {
+            // the client only sends while its event loop is polled
+            tokio::select! {
+                _ = changed.notified() => (),
+                _ = async {
+                    loop {
+                        eventloop.poll().await.unwrap();
+                    }
+                } => (),
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), notified)
+            .await
+            .expect("no change notified");
+        let state =
+            |rooms: &Mutex<Vec<Room>>| rooms.lock().unwrap()[0].actor.as_ref().unwrap().state;
+        assert_eq!(state(&rooms), HeatingState::Auto(21.5));
+        // rejected ones make Home Assistant show the actual state again
+        client
+            .publish(&set, QoS::AtLeastOnce, false, "99")
+            .await
+            .unwrap();
+        receive(&mut eventloop, &mut received, |r| {
+            r[&topic("Kitchen/climate")].0.contains("auto")
+        })
+        .await;
+        let climate: serde_json::Value =
+            serde_json::from_str(&received[&topic("Kitchen/climate")].0).unwrap();
+        assert_eq!(
+            climate,
+            json!({"mode": "auto", "preset": "none", "target": 21.5})
+        );
+        assert_eq!(state(&rooms), HeatingState::Auto(21.5));
+
+        // dropping the connection without a disconnect triggers the last will
+        rt.shutdown_background();
+        receive(&mut eventloop, &mut received, |r| {
+            r.get(&topic("status"))
+                .is_some_and(|(status, _)| status == "offline")
+        })
+        .await;
+
+        // clean up the retained messages
+        for topic in received.keys() {
+            client
+                .publish(topic, QoS::AtLeastOnce, true, "")
+                .await
+                .unwrap();
+        }
+        let cleared = |r: &Received| r.values().all(|(payload, _)| payload.is_empty());
+        receive(&mut eventloop, &mut received, cleared).await;
+    }
+}
@@ -0,0 +1,323 @@
+//! Safety interlocks applied between the controllers and the relays. They
+//! override whatever the heating state asks for.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyConfig {
+    /// Room temperature above which heating is always off
+    pub max_temperature: f32,
+    /// Room temperature below which heating is always on
+    pub frost_temperature: f32,
+    /// Longest time a relay may stay on without a break
+    pub max_on_minutes: u64,
+    /// Shortest time a relay stays on once switched on
+    pub min_on_seconds: u64,
+    /// Shortest time a relay stays off once switched off
+    pub min_off_seconds: u64,
+    /// Heating is off if the room's sensor has not reported for this long
+    pub stale_sensor_minutes: u64,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            max_temperature: 28.0,
+            frost_temperature: 5.0,
+            max_on_minutes: 240,
+            min_on_seconds: 120,
+            min_off_seconds: 120,
+            stale_sensor_minutes: 15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interlock {
+    MaxTemperature,
+    FrostProtection,
+    MaxOnTime,
+    MinOnTime,
+    MinOffTime,
+    StaleSensor,
+}
+
+impl Interlock {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Interlock::MaxTemperature => "max temperature",
+            Interlock::FrostProtection => "frost protection",
+            Interlock::MaxOnTime => "max on-time",
+            Interlock::MinOnTime => "min on-time",
+            Interlock::MinOffTime => "min off-time",
+            Interlock::StaleSensor => "sensor offline",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SafetyState {
+    on: bool,
+    switched_at: Option<Instant>,
+    /// Interlock overriding the heating state right now
+    pub active: Option<Interlock>,
+}
+
+/// What the safety layer knows about a room's sensor.
+#[derive(Debug, PartialEq)]
+pub enum SensorStatus {
+    /// The room has no sensor
+    None,
+    /// The room has a sensor, with its latest temperature and its age
+    Reading(Option<(f32, Duration)>),
+}
+
+impl SafetyConfig {
+    /// Returns whether the relay may be on at `now` when the heating state
+    /// asks for `want_on`. Activations are logged with `name`.
+    pub fn check(
+        &self,
+        name: &str,
+        state: &mut SafetyState,
+        want_on: bool,
+        sensor: SensorStatus,
+        now: Instant,
+    ) -> bool {
+        let since_switch = state.switched_at.map(|at| now.duration_since(at));
+        let stale = Duration::from_secs(60 * self.stale_sensor_minutes);
+
+        let temperature = match sensor {
+            SensorStatus::None => Ok(None),
+            SensorStatus::Reading(Some((temperature, age))) if age < stale => Ok(Some(temperature)),
+            SensorStatus::Reading(_) => Err(Interlock::StaleSensor),
+        };
+
+        let interlock = match temperature {
+            Err(interlock) => Some((interlock, false)),
+            Ok(Some(t)) if t >= self.max_temperature => Some((Interlock::MaxTemperature, false)),
+            _ if state.on
+                && since_switch
+                    .is_some_and(|d| d >= Duration::from_secs(60 * self.max_on_minutes)) =>
+            {
+                Some((Interlock::MaxOnTime, false))
+            }
+            Ok(Some(t)) if t <= self.frost_temperature => Some((Interlock::FrostProtection, true)),
+            _ => None,
+        };
+        let mut on = interlock.map_or(want_on, |(_, on)| on);
+
+        // protect the relay from switching too often, unless it has to be off
+        let forced_off = interlock.is_some_and(|(_, on)| !on);
+        let mut hold = None;
+        if !forced_off
+            && on != state.on
+            && let Some(since_switch) = since_switch
+        {
+            if state.on && since_switch < Duration::from_secs(self.min_on_seconds) {
+                hold = Some(Interlock::MinOnTime);
+            } else if !state.on && since_switch < Duration::from_secs(self.min_off_seconds) {
+                hold = Some(Interlock::MinOffTime);
+            }
+        }
+        if hold.is_some() {
+            on = state.on;
+        }
+
+        // only interlocks that change the outcome are reported
+        let active = hold.or(interlock.map(|(i, _)| i)).filter(|_| on != want_on);
+        if active != state.active {
+            match active {
+                Some(interlock) => println!(
+                    "{name}: safety interlock '{}' keeps heating {}",
+                    interlock.describe(),
+                    if on { "on" } else { "off" }
+                ),
+                None => println!("{name}: safety interlocks released"),
+            }
+            state.active = active;
+        }
+
+        if on != state.on || state.switched_at.is_none() {
+            state.on = on;
+            state.switched_at = Some(now);
+        }
+        on
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60;
+
+    fn fresh(temperature: f32) -> SensorStatus {
+        SensorStatus::Reading(Some((temperature, Duration::ZERO)))
+    }
+
+    #[test]
+    fn interlocks() {
+        let config = SafetyConfig::default();
+        // relay state, seconds since it switched, heating state wants on,
+        // sensor, expected relay state and reported interlock
+        let cases = [
+            (false, 600, true, fresh(20.0), true, None),
+            (true, 600, false, fresh(20.0), false, None),
+            (false, 600, true, SensorStatus::None, true, None),
+            (
+                false,
+                600,
+                true,
+                fresh(28.0),
+                false,
+                Some(Interlock::MaxTemperature),
+            ),
+            (false, 600, true, fresh(27.9), true, None),
+            (
+                true,
+                600,
+                false,
+                fresh(5.0),
+                true,
+                Some(Interlock::FrostProtection),
+            ),
+            (true, 600, false, fresh(5.1), false, None),
+            (
+                true,
+                240 * MINUTE,
+                true,
+                fresh(20.0),
+                false,
+                Some(Interlock::MaxOnTime),
+            ),
+            (true, 240 * MINUTE - 1, true, fresh(20.0), true, None),
+            // max on-time wins over frost protection
+            (true, 240 * MINUTE, false, fresh(4.0), false, None),
+            (
+                true,
+                240 * MINUTE,
+                true,
+                fresh(4.0),
+                false,
+                Some(Interlock::MaxOnTime),
+            ),
+            (
+                true,
+                119,
+                false,
+                fresh(20.0),
+                true,
+                Some(Interlock::MinOnTime),
+            ),
+            (true, 120, false, fresh(20.0), false, None),
+            (
+                false,
+                119,
+                true,
+                fresh(20.0),
+                false,
+                Some(Interlock::MinOffTime),
+            ),
+            (false, 120, true, fresh(20.0), true, None),
+            // interlocks switching off don't wait for the min on-time
+            (
+                true,
+                60,
+                true,
+                fresh(30.0),
+                false,
+                Some(Interlock::MaxTemperature),
+            ),
+            (
+                true,
+                60,
+                true,
+                SensorStatus::Reading(None),
+                false,
+                Some(Interlock::StaleSensor),
+            ),
+            // frost protection does wait for the min off-time
+            (false, 119, false, fresh(4.0), false, None),
+            (
+                false,
+                600,
+                true,
+                SensorStatus::Reading(Some((20.0, Duration::from_secs(15 * MINUTE)))),
+                false,
+                Some(Interlock::StaleSensor),
+            ),
+            (
+                false,
+                600,
+                true,
+                SensorStatus::Reading(Some((20.0, Duration::from_secs(15 * MINUTE - 1)))),
+                true,
+                None,
+            ),
+            (
+                false,
+                600,
+                true,
+                SensorStatus::Reading(None),
+                false,
+                Some(Interlock::StaleSensor),
+            ),
+            (
+                true,
+                600,
+                true,
+                SensorStatus::Reading(None),
+                false,
+                Some(Interlock::StaleSensor),
+            ),
+        ];
+        for (i, case) in cases.into_iter().enumerate() {
+            let (on, since_switch, want_on, sensor, expected, active) = case;
+            let switched_at = Instant::now();
+            let mut state = SafetyState {
+                on,
+                switched_at: Some(switched_at),
+                active: None,
+            };
+            let now = switched_at + Duration::from_secs(since_switch);
+            assert_eq!(
+                config.check("test", &mut state, want_on, sensor, now),
+                expected,
+                "case {i}"
+            );
+            assert_eq!(state.active, active, "case {i}");
+            assert_eq!(state.on, expected, "case {i}");
+            let switched = if expected != on { now } else { switched_at };
+            assert_eq!(state.switched_at, Some(switched), "case {i}");
+        }
+    }
+
+    #[test]
+    fn first_check() {
+        let config = SafetyConfig::default();
+        let now = Instant::now();
+        // nothing to protect the relay from before the first switch
+        let mut state = SafetyState::default();
+        assert!(config.check("test", &mut state, true, fresh(20.0), now));
+        assert_eq!(state.switched_at, Some(now));
+        let mut state = SafetyState::default();
+        assert!(!config.check("test", &mut state, false, fresh(20.0), now));
+        assert_eq!(state.switched_at, Some(now));
+    }
+
+    #[test]
+    fn interlock_released() {
+        let config = SafetyConfig::default();
+        let start = Instant::now();
+        let mut state = SafetyState::default();
+        assert!(!config.check("test", &mut state, true, fresh(29.0), start));
+        assert_eq!(state.active, Some(Interlock::MaxTemperature));
+        let now = start + Duration::from_secs(60);
+        assert!(!config.check("test", &mut state, true, fresh(27.0), now));
+        assert_eq!(state.active, Some(Interlock::MinOffTime));
+        let now = start + Duration::from_secs(120);
+        assert!(config.check("test", &mut state, true, fresh(27.0), now));
+        assert_eq!(state.active, None);
+    }
+}
@@ -0,0 +1,267 @@
+//! Weekly heating schedules. A schedule is a list of blocks, each starting
+//! at a time of day on some weekdays and lasting until the next block starts.
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, TimeDelta, Timelike, Weekday};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    Comfort,
+    Eco,
+    Night,
+    Frost,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [Preset::Comfort, Preset::Eco, Preset::Night, Preset::Frost];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Comfort => "comfort",
+            Preset::Eco => "eco",
+            Preset::Night => "night",
+            Preset::Frost => "frost",
+        }
+    }
+}
+
+/// Target temperature of each preset.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Presets {
+    pub comfort: f32,
+    pub eco: f32,
+    pub night: f32,
+    pub frost: f32,
+}
+
+impl Default for Presets {
+    fn default() -> Self {
+        Self {
+            comfort: 21.0,
+            eco: 18.5,
+            night: 17.0,
+            frost: 7.0,
+        }
+    }
+}
+
+impl Presets {
+    pub fn temperature(&self, preset: Preset) -> f32 {
+        match preset {
+            Preset::Comfort => self.comfort,
+            Preset::Eco => self.eco,
+            Preset::Night => self.night,
+            Preset::Frost => self.frost,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleBlock {
+    /// Days the block starts on, e.g. `["mon", "tue"]`
+    pub days: Vec<Weekday>,
+    /// Local time the block starts at, `HH:MM`
+    #[serde(with = "hh_mm")]
+    pub start: NaiveTime,
+    pub preset: Preset,
+}
+
+impl ScheduleBlock {
+    pub fn new(preset: Preset) -> Self {
+        Self {
+            days: WEEKDAYS.to_vec(),
+            start: NaiveTime::MIN,
+            preset,
+        }
+    }
+}
+
+pub const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+const WEEK_MINUTES: i64 = 7 * 24 * 60;
+
+/// Minutes since the start of the week (Monday 00:00)
+fn week_minute(day: Weekday, time: NaiveTime) -> i64 {
+    (day.num_days_from_monday() * 24 * 60 + time.hour() * 60 + time.minute()) as i64
+}
+
+fn starts(blocks: &[ScheduleBlock]) -> impl Iterator<Item = (i64, Preset)> {
+    blocks.iter().flat_map(|block| {
+        block
+            .days
+            .iter()
+            .map(|day| (week_minute(*day, block.start), block.preset))
+    })
+}
+
+fn truncate_to_minute(now: NaiveDateTime) -> NaiveDateTime {
+    now.with_second(0)
+        .and_then(|now| now.with_nanosecond(0))
+        .unwrap_or(now)
+}
+
+/// Returns the block active at `now` (local time) as its start time and preset.
+/// The start time identifies the block occurrence, so a change in it means a
+/// new block began.
+pub fn active(blocks: &[ScheduleBlock], now: NaiveDateTime) -> Option<(NaiveDateTime, Preset)> {
+    let now_minute = week_minute(now.weekday(), now.time());
+    let (age, preset) = starts(blocks)
+        .map(|(start, preset)| ((now_minute - start).rem_euclid(WEEK_MINUTES), preset))
+        .min_by_key(|(age, _)| *age)?;
+    Some((truncate_to_minute(now) - TimeDelta::minutes(age), preset))
+}
+
+/// Returns when the next block after `now` starts.
+pub fn next_change(blocks: &[ScheduleBlock], now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let now_minute = week_minute(now.weekday(), now.time());
+    let wait = starts(blocks)
+        .map(|(start, _)| (start - now_minute - 1).rem_euclid(WEEK_MINUTES) + 1)
+        .min()?;
+    Some(truncate_to_minute(now) + TimeDelta::minutes(wait))
+}
+
+mod hh_mm {
+    use chrono::NaiveTime;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&time.format("%H:%M").to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&s, "%H:%M").map_err(Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `day` of the week starting Monday 2024-01-01, at `time`
+    fn at(day: Weekday, time: &str) -> NaiveDateTime {
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+            + TimeDelta::days(day.num_days_from_monday() as i64);
+        date.and_time(NaiveTime::parse_from_str(time, "%H:%M:%S").unwrap())
+    }
+
+    fn block(days: &[Weekday], start: &str, preset: Preset) -> ScheduleBlock {
+        ScheduleBlock {
+            days: days.to_vec(),
+            start: NaiveTime::parse_from_str(start, "%H:%M").unwrap(),
+            preset,
+        }
+    }
+
+    #[test]
+    fn empty_schedule() {
+        let now = at(Weekday::Wed, "12:00:00");
+        assert_eq!(active(&[], now), None);
+        assert_eq!(next_change(&[], now), None);
+    }
+
+    #[test]
+    fn single_block() {
+        let blocks = [block(&[Weekday::Wed], "06:30", Preset::Comfort)];
+        // active all week, starting at its last occurrence
+        assert_eq!(
+            active(&blocks, at(Weekday::Wed, "06:30:00")),
+            Some((at(Weekday::Wed, "06:30:00"), Preset::Comfort))
+        );
+        assert_eq!(
+            active(&blocks, at(Weekday::Wed, "06:29:59")),
+            Some((
+                at(Weekday::Wed, "06:30:00") - TimeDelta::weeks(1),
+                Preset::Comfort
+            ))
+        );
+        assert_eq!(
+            active(&blocks, at(Weekday::Mon, "00:00:00")),
+            Some((
+                at(Weekday::Wed, "06:30:00") - TimeDelta::weeks(1),
+                Preset::Comfort
+            ))
+        );
+        // the next change is the next occurrence, never now
+        assert_eq!(
+            next_change(&blocks, at(Weekday::Wed, "06:29:59")),
+            Some(at(Weekday::Wed, "06:30:00"))
+        );
+        assert_eq!(
+            next_change(&blocks, at(Weekday::Wed, "06:30:00")),
+            Some(at(Weekday::Wed, "06:30:00") + TimeDelta::weeks(1))
+        );
+    }
+
+    #[test]
+    fn sunday_to_monday() {
+        let blocks = [
+            block(&[Weekday::Mon], "07:00", Preset::Comfort),
+            block(&[Weekday::Sun], "22:00", Preset::Night),
+        ];
+        // the Sunday block lasts over the end of the week
+        let sunday_night = at(Weekday::Sun, "22:00:00") - TimeDelta::weeks(1);
+        assert_eq!(
+            active(&blocks, at(Weekday::Sun, "23:59:59") - TimeDelta::weeks(1)),
+            Some((sunday_night, Preset::Night))
+        );
+        assert_eq!(
+            active(&blocks, at(Weekday::Mon, "00:00:00")),
+            Some((sunday_night, Preset::Night))
+        );
+        assert_eq!(
+            active(&blocks, at(Weekday::Mon, "06:59:00")),
+            Some((sunday_night, Preset::Night))
+        );
+        assert_eq!(
+            active(&blocks, at(Weekday::Mon, "07:00:30")),
+            Some((at(Weekday::Mon, "07:00:00"), Preset::Comfort))
+        );
+        assert_eq!(
+            next_change(&blocks, at(Weekday::Sun, "23:00:00")),
+            Some(at(Weekday::Mon, "07:00:00") + TimeDelta::weeks(1))
+        );
+        assert_eq!(
+            next_change(&blocks, at(Weekday::Mon, "08:00:00")),
+            Some(at(Weekday::Sun, "22:00:00"))
+        );
+    }
+
+    #[test]
+    fn equal_start_times() {
+        let blocks = [
+            block(&[Weekday::Mon, Weekday::Tue], "08:00", Preset::Eco),
+            block(&[Weekday::Tue], "08:00", Preset::Comfort),
+            block(&[Weekday::Tue], "18:00", Preset::Night),
+        ];
+        // the first of the blocks starting at the same time wins
+        assert_eq!(
+            active(&blocks, at(Weekday::Tue, "12:00:00")),
+            Some((at(Weekday::Tue, "08:00:00"), Preset::Eco))
+        );
+        assert_eq!(
+            next_change(&blocks, at(Weekday::Tue, "07:59:00")),
+            Some(at(Weekday::Tue, "08:00:00"))
+        );
+        assert_eq!(
+            next_change(&blocks, at(Weekday::Tue, "08:00:00")),
+            Some(at(Weekday::Tue, "18:00:00"))
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::HashSet;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// A single decoded measurement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub temperature: f32,
+    pub humidity: u8,
+}
+
+/// A decoded notification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// The current reading
+    Live(Measurement),
+    /// Samples from the on-device log with their age
+    History(Vec<(Duration, Measurement)>),
+}
+
+/// Support for one model of GATT connected thermometer.
+pub trait SensorDriver: Send + Sync {
+    /// Model name used in log messages
+    fn model(&self) -> &'static str;
+
+    /// Whether a discovered device is handled by this driver, judged by its
+    /// advertised name and service UUIDs.
+    fn matches(&self, name: Option<&str>, uuids: &HashSet<Uuid>) -> bool;
+
+    /// Characteristic that delivers the measurement frames as notifications
+    fn characteristic(&self) -> Uuid;
+
+    /// Characteristic commands are written to, if the model takes any
+    fn command_characteristic(&self) -> Option<Uuid> {
+        None
+    }
+
+    /// Command that makes the sensor send its on-device log, if it keeps one.
+    /// It is sent after every (re)connection.
+    fn history_request(&self) -> Option<&'static [u8]> {
+        None
+    }
+
+    /// Number of additional connection attempts before giving up
+    fn connect_retries(&self) -> u32 {
+        2
+    }
+
+    /// Decodes a frame received from `characteristic()`.
+    fn decode(&self, frame: &[u8]) -> Option<Frame>;
+}
+
+/// Turns the ages of log samples into timestamps, counting back from `now`.
+pub fn timestamp_samples(
+    now: DateTime<Utc>,
+    samples: Vec<(Duration, Measurement)>,
+) -> Vec<(DateTime<Utc>, Measurement)> {
+    samples
+        .into_iter()
+        .filter_map(|(age, m)| Some((now - TimeDelta::from_std(age).ok()?, m)))
+        .collect()
+}
+
+/// All supported sensor models, tried in order.
+pub static DRIVERS: &[&dyn SensorDriver] = &[&Tp357];
+
+pub fn find_driver(name: Option<&str>, uuids: &HashSet<Uuid>) -> Option<&'static dyn SensorDriver> {
+    DRIVERS.iter().copied().find(|d| d.matches(name, uuids))
+}
+
+/// ThermoPro TP357 / TP357S
+pub struct Tp357;
+
+impl SensorDriver for Tp357 {
+    fn model(&self) -> &'static str {
+        "TP357"
+    }
+
+    fn matches(&self, name: Option<&str>, _uuids: &HashSet<Uuid>) -> bool {
+        name.is_some_and(|name| name.starts_with("TP357"))
+    }
+
+    fn characteristic(&self) -> Uuid {
+        Uuid::from_u128(0x000102030405060708090a0b0c0d2b10)
+    }
+
+    fn command_characteristic(&self) -> Option<Uuid> {
+        Some(Uuid::from_u128(0x000102030405060708090a0b0c0d2b11))
+    }
+
+    /// Asks for the log of the last 24 hours.
+    fn history_request(&self) -> Option<&'static [u8]> {
+        Some(&[0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7a])
+    }
+
+    /// Live frames look like `c2 xx xx TL TH HU ...` with the temperature in
+    /// 0.1°C as little endian signed 16 bit and the humidity in percent.
+    ///
+    /// Log frames look like `a7 NH NL` followed by up to five `TL TH HU`
+    /// samples, one minute apart. `N` is the big endian index of the first
+    /// sample, counted backwards from the newest one (0). The log ends with
+    /// an `a7 ff ff` frame.
+    fn decode(&self, frame: &[u8]) -> Option<Frame> {
+        if frame.first() == Some(&0xa7) {
+            if frame.len() < 3 {
+                return None;
+            }
+            let first = u16::from_be_bytes([frame[1], frame[2]]);
+            if first == 0xffff {
+                return Some(Frame::History(Vec::new()));
+            }
+            let samples = frame[3..]
+                .chunks_exact(3)
+                .enumerate()
+                .map(|(i, s)| {
+                    let age = Duration::from_secs(60 * (first as u64).saturating_sub(i as u64));
+                    let measurement = Measurement {
+                        temperature: i16::from_le_bytes([s[0], s[1]]) as f32 / 10.0,
+                        humidity: s[2],
+                    };
+                    (age, measurement)
+                })
+                .collect();
+            return Some(Frame::History(samples));
+        }
+
+        if frame.len() < 6 || frame[0] != 0xc2 {
+            return None;
+        }
+        let temperature = i16::from_le_bytes([frame[3], frame[4]]) as f32 / 10.0;
+        Some(Frame::Live(Measurement {
+            temperature,
+            humidity: frame[5],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live(temperature: f32, humidity: u8) -> Option<Frame> {
+        Some(Frame::Live(Measurement {
+            temperature,
+            humidity,
+        }))
+    }
+
+    #[test]
+    fn tp357_live_frames() {
+        let frames: [(&[u8], _); 3] = [
+            (&[0xc2, 0x00, 0x00, 0xd7, 0x00, 0x2d, 0x2c], live(21.5, 45)),
+            (&[0xc2, 0x00, 0x00, 0xce, 0xff, 0x50, 0x2c], live(-5.0, 80)),
+            (&[0xc2, 0x01, 0x00, 0x00, 0x00, 0x00], live(0.0, 0)),
+        ];
+        for (frame, expected) in frames {
+            assert_eq!(Tp357.decode(frame), expected, "{frame:02x?}");
+        }
+    }
+
+    #[test]
+    fn tp357_history_frames() {
+        let frame = [
+            0xa7, 0x00, 0x04, 0xd7, 0x00, 0x2d, 0xd2, 0x00, 0x2e, 0xcd, 0x00, 0x2f, 0xc8,
+        ];
+        let minutes = |m: u64| Duration::from_secs(60 * m);
+        let measurement = |temperature, humidity| Measurement {
+            temperature,
+            humidity,
+        };
+        // the incomplete sample at the end is left out
+        assert_eq!(
+            Tp357.decode(&frame),
+            Some(Frame::History(vec![
+                (minutes(4), measurement(21.5, 45)),
+                (minutes(3), measurement(21.0, 46)),
+                (minutes(2), measurement(20.5, 47)),
+            ]))
+        );
+        // five samples starting at the newest one, ages don't go below zero
+        let frame = [
+            0xa7, 0x00, 0x01, 0xd7, 0x00, 0x2d, 0xd7, 0x00, 0x2d, 0xd7, 0x00, 0x2d,
+        ];
+        let Some(Frame::History(samples)) = Tp357.decode(&frame) else {
+            panic!("not a history frame");
+        };
+        let ages: Vec<_> = samples.iter().map(|(age, _)| *age).collect();
+        assert_eq!(ages, [minutes(1), minutes(0), minutes(0)]);
+        // end of the log
+        assert_eq!(
+            Tp357.decode(&[0xa7, 0xff, 0xff]),
+            Some(Frame::History(Vec::new()))
+        );
+        assert_eq!(
+            Tp357.decode(&[0xa7, 0x00, 0x00]),
+            Some(Frame::History(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn tp357_history_request() {
+        let request = Tp357.history_request().unwrap();
+        assert_eq!(request.len(), 8);
+        assert_eq!(request[0], 0xa7);
+    }
+
+    #[test]
+    fn history_timestamps() {
+        let now = DateTime::parse_from_rfc3339("2024-03-31T01:02:00Z")
+            .unwrap()
+            .to_utc();
+        let m = Measurement {
+            temperature: 20.0,
+            humidity: 50,
+        };
+        let samples = vec![
+            (Duration::from_secs(24 * 3600), m),
+            (Duration::from_secs(90), m),
+            (Duration::ZERO, m),
+        ];
+        let timestamps: Vec<_> = timestamp_samples(now, samples)
+            .into_iter()
+            .map(|(t, _)| t.to_rfc3339())
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                "2024-03-30T01:02:00+00:00",
+                "2024-03-31T01:00:30+00:00",
+                "2024-03-31T01:02:00+00:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn tp357_malformed_frames() {
+        let frames: [&[u8]; 5] = [
+            &[],
+            &[0xc2, 0x00, 0x00, 0xd7, 0x00],
+            &[0x00, 0x00, 0x00, 0xd7, 0x00, 0x2d, 0x2c],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0xa7, 0x00],
+        ];
+        for frame in frames {
+            assert_eq!(Tp357.decode(frame), None, "{frame:02x?}");
+        }
+    }
+}
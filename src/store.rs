@@ -0,0 +1,511 @@
+//! Long-term sensor history in an SQLite database. Every reading is kept for
+//! a while and rolled up into 5 minute, hourly and daily min/avg/max values
+//! that are kept much longer.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OpenFlags, params};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::data::TPSensorData;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Path of the SQLite database
+    pub database: String,
+    /// Days to keep every single reading
+    pub raw_days: u64,
+    /// Days to keep the 5 minute rollups
+    pub five_minute_days: u64,
+    /// Days to keep the hourly rollups
+    pub hourly_days: u64,
+    /// Days to keep the daily rollups, 0 keeps them forever
+    pub daily_days: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            database: "history.db".to_string(),
+            raw_days: 7,
+            five_minute_days: 90,
+            hourly_days: 730,
+            daily_days: 0,
+        }
+    }
+}
+
+/// A stored reading. The database keeps its timestamp in whole seconds since
+/// the epoch.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub data: TPSensorData,
+}
+
+/// Length of the rollup buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    FiveMinutes,
+    Hourly,
+    Daily,
+}
+
+impl Period {
+    const ALL: [Period; 3] = [Period::FiveMinutes, Period::Hourly, Period::Daily];
+
+    pub fn seconds(self) -> i64 {
+        match self {
+            Period::FiveMinutes => 5 * 60,
+            Period::Hourly => 60 * 60,
+            Period::Daily => DAY,
+        }
+    }
+}
+
+/// Aggregated readings of one rollup bucket.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Rollup {
+    pub start: DateTime<Utc>,
+    pub count: u32,
+    pub temperature_min: f32,
+    pub temperature_avg: f32,
+    pub temperature_max: f32,
+    pub humidity_min: f32,
+    pub humidity_avg: f32,
+    pub humidity_max: f32,
+}
+
+/// Interval at which rollups are updated and old data is removed
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const DAY: i64 = 24 * 60 * 60;
+
+pub struct Store {
+    conn: Connection,
+    config: HistoryConfig,
+    /// Oldest sample written since the rollups were last updated
+    dirty_since: Option<i64>,
+}
+
+impl Store {
+    pub fn open(config: &HistoryConfig) -> anyhow::Result<Self> {
+        let conn = Connection::open(&config.database)
+            .with_context(|| format!("Failed to open history database {}", config.database))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS readings (
+                sensor TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                temperature REAL NOT NULL,
+                humidity INTEGER NOT NULL,
+                PRIMARY KEY (sensor, timestamp)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS rollups (
+                sensor TEXT NOT NULL,
+                period INTEGER NOT NULL,
+                start INTEGER NOT NULL,
+                count INTEGER NOT NULL,
+                temperature_min REAL NOT NULL,
+                temperature_avg REAL NOT NULL,
+                temperature_max REAL NOT NULL,
+                humidity_min REAL NOT NULL,
+                humidity_avg REAL NOT NULL,
+                humidity_max REAL NOT NULL,
+                PRIMARY KEY (sensor, period, start)
+            ) WITHOUT ROWID;",
+        )
+        .context("Failed to create history tables")?;
+        Ok(Self {
+            conn,
+            config: config.clone(),
+            // catch up on everything that was not rolled up before the last exit
+            dirty_since: Some(Utc::now().timestamp() - config.raw_days as i64 * DAY),
+        })
+    }
+
+    /// Appends samples. Samples of a sensor with the same second are dropped.
+    pub fn insert(&mut self, samples: &[Sample]) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT OR IGNORE INTO readings (sensor, timestamp, temperature, humidity)
+                VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for sample in samples {
+                stmt.execute(params![
+                    sample.data.address,
+                    sample.timestamp.timestamp(),
+                    sample.data.temperature,
+                    sample.data.humidity,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        if let Some(oldest) = samples.iter().map(|s| s.timestamp.timestamp()).min() {
+            self.dirty_since = Some(self.dirty_since.map_or(oldest, |since| since.min(oldest)));
+        }
+        Ok(())
+    }
+
+    /// Returns all readings since `since`, oldest first.
+    pub fn readings_since(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<Sample>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT sensor, timestamp, temperature, humidity FROM readings
+            WHERE timestamp >= ?1 ORDER BY timestamp",
+        )?;
+        let samples = stmt
+            .query_map([since.timestamp()], |row| {
+                Ok(Sample {
+                    timestamp: DateTime::from_timestamp(row.get(1)?, 0).unwrap_or_default(),
+                    data: TPSensorData {
+                        address: row.get(0)?,
+                        temperature: row.get(2)?,
+                        humidity: row.get(3)?,
+                    },
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(samples)
+    }
+
+    /// Recomputes the rollup buckets touched by samples written since the
+    /// last run.
+    fn rollup(&mut self) -> anyhow::Result<()> {
+        let Some(since) = self.dirty_since else {
+            return Ok(());
+        };
+        // buckets reaching back before the oldest kept reading would be
+        // replaced with partial values
+        let cutoff = Utc::now().timestamp() - self.config.raw_days as i64 * DAY;
+        let tx = self.conn.transaction()?;
+        for period in Period::ALL.map(Period::seconds) {
+            let complete_from = (cutoff + period - 1).div_euclid(period) * period;
+            let from = (since.div_euclid(period) * period).max(complete_from);
+            tx.execute(
+                "INSERT OR REPLACE INTO rollups
+                SELECT sensor, ?1, timestamp / ?1 * ?1, count(*),
+                    min(temperature), avg(temperature), max(temperature),
+                    min(humidity), avg(humidity), max(humidity)
+                FROM readings WHERE timestamp >= ?2
+                GROUP BY sensor, timestamp / ?1",
+                params![period, from],
+            )?;
+        }
+        tx.commit()?;
+        self.dirty_since = None;
+        Ok(())
+    }
+
+    /// Removes data that is past its retention.
+    fn expire(&mut self, now: i64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM readings WHERE timestamp < ?1",
+            [now - self.config.raw_days as i64 * DAY],
+        )?;
+        let retention = [
+            self.config.five_minute_days,
+            self.config.hourly_days,
+            self.config.daily_days,
+        ];
+        for (period, days) in Period::ALL.map(Period::seconds).into_iter().zip(retention) {
+            if days == 0 {
+                continue;
+            }
+            self.conn.execute(
+                "DELETE FROM rollups WHERE period = ?1 AND start < ?2",
+                params![period, now - days as i64 * DAY],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn maintain(&mut self) {
+        if let Err(e) = self.rollup() {
+            eprintln!("Failed to update history rollups: {e:#}");
+        }
+        if let Err(e) = self.expire(Utc::now().timestamp()) {
+            eprintln!("Failed to remove old history: {e:#}");
+        }
+    }
+
+    fn run(mut self, rx: Receiver<Vec<Sample>>) {
+        let mut next_maintenance = Instant::now();
+        loop {
+            match rx.recv_timeout(next_maintenance.saturating_duration_since(Instant::now())) {
+                Ok(samples) => {
+                    if let Err(e) = self.insert(&samples) {
+                        eprintln!("Failed to store {} samples: {e:#}", samples.len());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if Instant::now() >= next_maintenance {
+                self.maintain();
+                next_maintenance = Instant::now() + MAINTENANCE_INTERVAL;
+            }
+        }
+        self.maintain();
+    }
+
+    /// Moves the store to its own thread and returns a sender for new samples.
+    pub fn spawn(self) -> Sender<Vec<Sample>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || self.run(rx));
+        tx
+    }
+}
+
+/// Read-only access to the history database, e.g. from the API.
+pub struct HistoryReader {
+    conn: Connection,
+}
+
+impl HistoryReader {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open history database {path}"))?;
+        Ok(Self { conn })
+    }
+
+    /// Readings of `sensor` in `from..to`, oldest first.
+    pub fn readings(
+        &self,
+        sensor: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Sample>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT timestamp, temperature, humidity FROM readings
+            WHERE sensor = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp",
+        )?;
+        let samples = stmt
+            .query_map(params![sensor, from.timestamp(), to.timestamp()], |row| {
+                Ok(Sample {
+                    timestamp: DateTime::from_timestamp(row.get(0)?, 0).unwrap_or_default(),
+                    data: TPSensorData {
+                        address: sensor.to_string(),
+                        temperature: row.get(1)?,
+                        humidity: row.get(2)?,
+                    },
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(samples)
+    }
+
+    /// Rollups of `sensor` starting in `from..to`, oldest first.
+    pub fn rollups(
+        &self,
+        sensor: &str,
+        period: Period,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Rollup>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT start, count, temperature_min, temperature_avg, temperature_max,
+                humidity_min, humidity_avg, humidity_max
+            FROM rollups
+            WHERE sensor = ?1 AND period = ?2 AND start >= ?3 AND start < ?4 ORDER BY start",
+        )?;
+        let params = params![sensor, period.seconds(), from.timestamp(), to.timestamp()];
+        let rollups = stmt
+            .query_map(params, |row| {
+                Ok(Rollup {
+                    start: DateTime::from_timestamp(row.get(0)?, 0).unwrap_or_default(),
+                    count: row.get(1)?,
+                    temperature_min: row.get(2)?,
+                    temperature_avg: row.get(3)?,
+                    temperature_max: row.get(4)?,
+                    humidity_min: row.get(5)?,
+                    humidity_avg: row.get(6)?,
+                    humidity_max: row.get(7)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(rollups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENSOR: &str = "A4:C1:38:00:00:01";
+
+    /// Database file in the temp directory, removed when dropped.
+    struct TempDb(String);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("homectl-{}-{name}.db", std::process::id()));
+            let db = Self(path.to_string_lossy().into_owned());
+            db.remove();
+            db
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{suffix}", self.0));
+            }
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    /// Start of the current day, so that all buckets are complete rollups
+    fn today() -> i64 {
+        Utc::now().timestamp().div_euclid(DAY) * DAY
+    }
+
+    fn sample(sensor: &str, timestamp: i64, temperature: f32, humidity: u8) -> Sample {
+        Sample {
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            data: TPSensorData {
+                address: sensor.to_string(),
+                temperature,
+                humidity,
+            },
+        }
+    }
+
+    fn time(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    /// Start, count, min/avg/max temperature and humidity of the rollups
+    fn rollups(db: &TempDb, sensor: &str, period: Period) -> Vec<(i64, u32, [f32; 6])> {
+        let reader = HistoryReader::open(&db.0).unwrap();
+        reader
+            .rollups(sensor, period, time(0), time(today() + 2 * DAY))
+            .unwrap()
+            .into_iter()
+            .map(|r| {
+                let values = [
+                    r.temperature_min,
+                    r.temperature_avg,
+                    r.temperature_max,
+                    r.humidity_min,
+                    r.humidity_avg,
+                    r.humidity_max,
+                ];
+                (r.start.timestamp(), r.count, values)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rollup_buckets() {
+        let db = TempDb::new("rollup");
+        let config = HistoryConfig {
+            database: db.0.clone(),
+            ..Default::default()
+        };
+        let mut store = Store::open(&config).unwrap();
+        let t = today();
+        store
+            .insert(&[
+                sample(SENSOR, t + 299, 20.0, 40),
+                sample(SENSOR, t + 300, 21.0, 50),
+                sample(SENSOR, t + 301, 23.0, 60),
+                // same second, dropped
+                sample(SENSOR, t + 301, 30.0, 90),
+                sample(SENSOR, t + 3599, 19.0, 45),
+                sample(SENSOR, t + 3600, 22.0, 55),
+                sample("A4:C1:38:00:00:02", t + 300, 10.0, 10),
+            ])
+            .unwrap();
+        store.rollup().unwrap();
+
+        assert_eq!(
+            rollups(&db, SENSOR, Period::FiveMinutes),
+            [
+                (t, 1, [20.0, 20.0, 20.0, 40.0, 40.0, 40.0]),
+                (t + 300, 2, [21.0, 22.0, 23.0, 50.0, 55.0, 60.0]),
+                (t + 3300, 1, [19.0, 19.0, 19.0, 45.0, 45.0, 45.0]),
+                (t + 3600, 1, [22.0, 22.0, 22.0, 55.0, 55.0, 55.0]),
+            ]
+        );
+        assert_eq!(
+            rollups(&db, SENSOR, Period::Hourly),
+            [
+                (t, 4, [19.0, 20.75, 23.0, 40.0, 48.75, 60.0]),
+                (t + 3600, 1, [22.0, 22.0, 22.0, 55.0, 55.0, 55.0]),
+            ]
+        );
+        assert_eq!(
+            rollups(&db, SENSOR, Period::Daily),
+            [(t, 5, [19.0, 21.0, 23.0, 40.0, 50.0, 60.0])]
+        );
+        assert_eq!(
+            rollups(&db, "A4:C1:38:00:00:02", Period::Daily),
+            [(t, 1, [10.0, 10.0, 10.0, 10.0, 10.0, 10.0])]
+        );
+
+        // a late sample updates the buckets it falls into
+        store.insert(&[sample(SENSOR, t + 302, 25.0, 70)]).unwrap();
+        store.rollup().unwrap();
+        assert_eq!(
+            rollups(&db, SENSOR, Period::FiveMinutes)[1],
+            (t + 300, 3, [21.0, 23.0, 25.0, 50.0, 60.0, 70.0])
+        );
+        assert_eq!(rollups(&db, SENSOR, Period::Daily)[0].1, 6);
+    }
+
+    #[test]
+    fn expire_per_config() {
+        let db = TempDb::new("expire");
+        let config = HistoryConfig {
+            database: db.0.clone(),
+            raw_days: 1,
+            five_minute_days: 2,
+            hourly_days: 3,
+            daily_days: 0,
+        };
+        let mut store = Store::open(&config).unwrap();
+        let t = today();
+        store
+            .insert(&[
+                sample(SENSOR, t, 20.0, 50),
+                sample(SENSOR, t + 400, 21.0, 50),
+                sample(SENSOR, t + 3600, 22.0, 50),
+            ])
+            .unwrap();
+        store.rollup().unwrap();
+
+        // readings and 5 minute, hourly and daily rollups
+        let counts = |store: &Store| {
+            let count =
+                |sql: &str| -> i64 { store.conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+            [
+                count("SELECT count(*) FROM readings"),
+                count("SELECT count(*) FROM rollups WHERE period = 300"),
+                count("SELECT count(*) FROM rollups WHERE period = 3600"),
+                count("SELECT count(*) FROM rollups WHERE period = 86400"),
+            ]
+        };
+        assert_eq!(counts(&store), [3, 3, 2, 1]);
+
+        // readings are kept for exactly `raw_days`
+        store.expire(t + DAY + 400).unwrap();
+        assert_eq!(counts(&store), [2, 3, 2, 1]);
+        store.expire(t + DAY + 3601).unwrap();
+        assert_eq!(counts(&store), [0, 3, 2, 1]);
+        // rollups by their start
+        store.expire(t + 2 * DAY + 300).unwrap();
+        assert_eq!(counts(&store), [0, 2, 2, 1]);
+        store.expire(t + 3 * DAY + 3600).unwrap();
+        assert_eq!(counts(&store), [0, 0, 1, 1]);
+        // daily rollups are kept forever with `daily_days = 0`
+        store.expire(t + 1000 * DAY).unwrap();
+        assert_eq!(counts(&store), [0, 0, 0, 1]);
+    }
+}
@@ -1,85 +1,176 @@
-use eframe::egui::{Button, Color32, Context, Pos2, Rect, Stroke};
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, Timelike, Utc};
+use eframe::egui::{Button, Color32, Pos2, Rect, Stroke};
 use eframe::{CreationContext, egui};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use tokio::runtime::Runtime;
-use tokio::sync::mpsc::channel;
-use tokio_util::sync::CancellationToken;
+use std::time::Instant;
 
-use crate::data::{
-    create_rooms, save_rooms_to_file, update_actors, update_rooms, HeatingState, Room, SensorHistoryItem
-};
+use crate::bt::DiscoveryOptions;
+use crate::config::{AwayConfig, Config};
+use crate::daemon::Daemon;
+use crate::data::{Away, ConnectionState, HISTORY_LEN, HeatingActor, HeatingState};
+use crate::schedule::{self, Preset, Presets, ScheduleBlock, WEEKDAYS};
+
+/// Target temperature when switching a room to auto mode
+const DEFAULT_TARGET: f32 = 20.0;
+const TARGET_STEP: f32 = 0.5;
+const MIN_TARGET: f32 = 5.0;
+const MAX_TARGET: f32 = 28.0;
+
+/// Hours between the time labels of the history chart
+const CHART_TICK_HOURS: u32 = 6;
+
+/// Battery level below which the indicator turns red
+const LOW_BATTERY: u8 = 20;
 
 pub struct MyApp {
-    ct: CancellationToken,
-    rooms: Arc<Mutex<Vec<Room>>>,
+    daemon: Daemon,
+    presets: Presets,
+    away_config: AwayConfig,
+    /// Room whose schedule editor is open
+    schedule_editor: Option<String>,
 }
 
-async fn redraw_loop(ctx: Context, sleep_time: Duration) {
-    loop {
-        ctx.request_repaint();
-        tokio::time::sleep(sleep_time).await;
+fn signal_bars(rssi: i16) -> &'static str {
+    match rssi {
+        -60.. => "▂▄▆█",
+        -70.. => "▂▄▆",
+        -80.. => "▂▄",
+        _ => "▂",
     }
 }
 
-impl MyApp {
-    pub fn new(cc: &CreationContext) -> Self {
-        let rooms = Arc::new(Mutex::new(create_rooms()));
-
-        let rt = Runtime::new().expect("Unable to create Runtime");
-        let ct = CancellationToken::new();
-
-        // Enter the runtime so that `tokio::spawn` is available immediately.
-        let _enter = rt.enter();
-
-        // Execute the runtime in its own thread.
-        // The future doesn't have to do anything. In this example, it just sleeps
-        // forever.
-        let ct_clone = ct.clone();
-        let ctx_clone = cc.egui_ctx.clone();
-        let rooms_clone = rooms.clone();
-        std::thread::spawn(move || {
-            rt.block_on(async {
-                let (tx, rx) = channel(10);
-                let redraw = tokio::spawn(redraw_loop(ctx_clone.clone(), Duration::from_secs(1)));
-                let handle = tokio::spawn(crate::bt::bt_main(tx));
-                let update_rooms_handle = tokio::spawn(update_rooms(rx, rooms_clone.clone(), ctx_clone));
-                //let update_actors_handle = tokio::spawn(update_actors(rooms_clone.clone()));
+/// Describes the schedule's current block, e.g. `eco until 17:00`.
+fn schedule_text(actor: &HeatingActor, presets: &Presets) -> Option<String> {
+    let now = Local::now().naive_local();
+    let (_, preset) = schedule::active(&actor.schedule, now)?;
+    let next = schedule::next_change(&actor.schedule, now)?;
+    let until = if next.date() == now.date() {
+        next.format("%H:%M")
+    } else {
+        next.format("%a %H:%M")
+    };
+    if actor.state == HeatingState::Auto(presets.temperature(preset)) {
+        Some(format!("{} until {until}", preset.name()))
+    } else {
+        Some(format!("override until {until}"))
+    }
+}
 
-                tokio::select! {
-                    _ = tokio::signal::ctrl_c() => {
-                        println!("Ctrl-C received, shutting down");
-                        ct_clone.cancel();
-                    }
-                    _ = ct_clone.cancelled() => {
-                        println!("Cancellation requested, shutting down");
-                    }
-                    res = handle => {
-                        println!("shutdown bt");
-                        if let Err(err) = res {
-                            eprintln!("Error: {}", err);
-                        }
-                    }
-                    res = update_rooms_handle => {
-                        println!("shutdown rooms");
-                        if let Err(err) = res {
-                            eprintln!("Error in update_rooms: {}", err);
-                        }
-                    }
-                    /*res = update_actors_handle => {
-                        println!("shutdown actors");
-                        if let Err(err) = res {
-                            eprintln!("Error in update_actors: {}", err);
-                        }
-                    }*/
-                    _ = redraw => {
-                        println!("shutdown redraw");
+/// Editor for the blocks of one schedule. Returns whether anything changed.
+fn schedule_editor(ui: &mut egui::Ui, blocks: &mut Vec<ScheduleBlock>, presets: &Presets) -> bool {
+    let mut changed = false;
+    let mut remove = None;
+    for (i, block) in blocks.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            for day in WEEKDAYS {
+                let selected = block.days.contains(&day);
+                if ui
+                    .selectable_label(selected, &day.to_string()[..2])
+                    .clicked()
+                {
+                    if selected {
+                        block.days.retain(|d| *d != day);
+                    } else {
+                        block.days.push(day);
                     }
+                    changed = true;
                 }
-            })
+            }
+            let mut hour = block.start.hour();
+            let mut minute = block.start.minute();
+            let hour_changed = ui
+                .add(egui::DragValue::new(&mut hour).range(0..=23))
+                .changed();
+            ui.label(":");
+            let minute_changed = ui
+                .add(egui::DragValue::new(&mut minute).range(0..=59).speed(0.2))
+                .changed();
+            if (hour_changed || minute_changed)
+                && let Some(start) = NaiveTime::from_hms_opt(hour, minute, 0)
+            {
+                block.start = start;
+                changed = true;
+            }
+            egui::ComboBox::from_id_salt(("preset", i))
+                .selected_text(block.preset.name())
+                .show_ui(ui, |ui| {
+                    for preset in Preset::ALL {
+                        let text =
+                            format!("{} ({:.1}°C)", preset.name(), presets.temperature(preset));
+                        changed |= ui
+                            .selectable_value(&mut block.preset, preset, text)
+                            .changed();
+                    }
+                });
+            if ui.button("🗑").clicked() {
+                remove = Some(i);
+            }
+        });
+    }
+    if let Some(i) = remove {
+        blocks.remove(i);
+        changed = true;
+    }
+    if ui.button("➕ Add block").clicked() {
+        blocks.push(ScheduleBlock::new(Preset::Comfort));
+        changed = true;
+    }
+    changed
+}
+
+/// Controls for the house-wide away mode.
+fn away_panel(ui: &mut egui::Ui, away: &mut Option<Away>, config: &AwayConfig, presets: &Presets) {
+    ui.horizontal(|ui| {
+        if ui.selectable_label(away.is_some(), "🏠 Away").clicked() {
+            *away = match away {
+                Some(_) => None,
+                None => Some(Away { until: None }),
+            };
+        }
+        let Some(away) = away else {
+            return;
+        };
+        let now = Local::now().naive_local();
+        match away.until {
+            Some(until) => ui.label(format!("back {}", until.format("%a %d.%m. %H:%M"))),
+            None => ui.label("no return date"),
+        };
+        // new return dates start at the next full hour
+        let base = away.until.unwrap_or_else(|| {
+            now.date().and_hms_opt(now.hour(), 0, 0).unwrap_or(now) + TimeDelta::hours(1)
         });
+        for (label, delta) in [
+            ("-1d", TimeDelta::days(-1)),
+            ("+1d", TimeDelta::days(1)),
+            ("-1h", TimeDelta::hours(-1)),
+            ("+1h", TimeDelta::hours(1)),
+        ] {
+            if ui.button(label).clicked() {
+                let until = if away.until.is_some() {
+                    base + delta
+                } else {
+                    base
+                };
+                away.until = Some(until.max(now));
+            }
+        }
+        if away.until.is_some() && ui.button("✖").clicked() {
+            away.until = None;
+        }
+        match away.target(config, presets, now) {
+            Some(target) => ui.label(format!("auto rooms at most {target:.1}°C")),
+            None => ui.label("pre-heating"),
+        };
+    });
+}
 
-        Self { ct, rooms }
+impl MyApp {
+    pub fn new(cc: &CreationContext, config: Config, discovery: DiscoveryOptions) -> Self {
+        Self {
+            daemon: Daemon::start(&config, Some(cc.egui_ctx.clone()), discovery),
+            presets: config.presets,
+            away_config: config.away,
+            schedule_editor: None,
+        }
     }
 }
 
@@ -89,19 +180,28 @@ impl eframe::App for MyApp {
     // }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        if self.ct.is_cancelled() {
+        if self.daemon.ct.is_cancelled() {
             println!("Application is exiting, closing window.");
             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
         }
 
-        let mut rooms = self.rooms.lock().unwrap();
-        let history_len = Duration::from_secs(24 * 60 * 60);
+        egui::TopBottomPanel::top("away").show(ctx, |ui| {
+            away_panel(
+                ui,
+                &mut self.daemon.away.lock().unwrap(),
+                &self.away_config,
+                &self.presets,
+            );
+        });
+
+        let mut rooms = self.daemon.rooms.lock().unwrap();
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            let row_height = 480.0 / rooms.len() as f32;
+            let top = ui.clip_rect().top();
+            let row_height = (480.0 - top) / rooms.len() as f32;
             let row_width = 800.0;
             let margin = row_height / 20.0;
-            let mut pos = 0.0;
+            let mut pos = top;
             for room in &mut *rooms {
                 let col = if let Some(sensor) = &room.sensor {
                     if sensor.temperature < 16.0 {
@@ -134,18 +234,65 @@ impl eframe::App for MyApp {
                     },
                     egui::StrokeKind::Middle,
                 );
-                ui.painter().text(
+                let name_rect = ui.painter().text(
                     egui::pos2(2.0 * margin, pos + 2.0 * margin),
                     egui::Align2::LEFT_TOP,
                     &room.name,
                     egui::FontId::proportional(row_height / 4.0),
                     Color32::BLACK,
                 );
+                if room.window_detected(Instant::now()) {
+                    let galley = ui.painter().layout_no_wrap(
+                        "window open".to_string(),
+                        egui::FontId::proportional(row_height / 7.0),
+                        Color32::WHITE,
+                    );
+                    let badge = Rect::from_min_size(
+                        egui::pos2(
+                            name_rect.right() + margin,
+                            name_rect.center().y - galley.size().y / 2.0,
+                        ),
+                        galley.size(),
+                    )
+                    .expand(margin / 2.0);
+                    ui.painter().rect_filled(badge, margin, Color32::RED);
+                    ui.painter().galley(
+                        badge.min + egui::vec2(margin / 2.0, margin / 2.0),
+                        galley,
+                        Color32::WHITE,
+                    );
+                }
+                if room.battery.is_some() || room.rssi.is_some() {
+                    let mut status = String::new();
+                    if let Some(battery) = room.battery {
+                        status += &format!("🔋{battery}% ");
+                    }
+                    if let Some(rssi) = room.rssi {
+                        status += signal_bars(rssi);
+                    }
+                    let low_battery = room.battery.is_some_and(|b| b < LOW_BATTERY);
+                    ui.painter().text(
+                        egui::pos2(row_width / 3.0 - margin, pos + 2.0 * margin),
+                        egui::Align2::RIGHT_TOP,
+                        status,
+                        egui::FontId::proportional(row_height / 6.0),
+                        if low_battery {
+                            Color32::RED
+                        } else {
+                            Color32::BLACK
+                        },
+                    );
+                }
                 if let Some(sensor) = &room.sensor {
                     ui.painter().text(
                         egui::pos2(2.0 * margin, pos + row_height / 2.0 + 2.0 * margin),
                         egui::Align2::LEFT_TOP,
-                        format!("{:.1}°C ({}s) {}%", sensor.temperature, (room.sensor_ttl.unwrap_or(Instant::now()) - Instant::now()).as_secs(), sensor.humidity),
+                        format!(
+                            "{:.1}°C ({}s) {}%",
+                            sensor.temperature,
+                            (room.sensor_ttl.unwrap_or(Instant::now()) - Instant::now()).as_secs(),
+                            sensor.humidity
+                        ),
                         egui::FontId::proportional(row_height / 4.0),
                         Color32::BLACK,
                     );
@@ -176,65 +323,188 @@ impl eframe::App for MyApp {
                     let max_temp = 23.0;
                     let min_temp = 17.0;
 
-                    for SensorHistoryItem { data, timestamp } in room
-                        .sensor_history
-                        .iter()
-                        .enumerate()
-                        .filter_map(|(i, item)| if i % 10 == 0 { Some(item) } else { None })
+                    let now = Utc::now();
+                    let time_x = |timestamp: DateTime<Utc>| {
+                        x_max
+                            - width / HISTORY_LEN.as_seconds_f32()
+                                * (now - timestamp).as_seconds_f32()
+                    };
+
+                    // times of day every few hours
+                    let local_now = now.with_timezone(&Local);
+                    let last_tick = local_now
+                        .with_hour(local_now.hour() / CHART_TICK_HOURS * CHART_TICK_HOURS)
+                        .and_then(|t| t.with_minute(0))
+                        .and_then(|t| t.with_second(0))
+                        .and_then(|t| t.with_nanosecond(0));
+                    let ticks = (0..).map_while(|i| {
+                        let tick = last_tick? - TimeDelta::hours((i * CHART_TICK_HOURS) as i64);
+                        (now - tick.to_utc() < HISTORY_LEN).then_some(tick)
+                    });
+                    for tick in ticks {
+                        let x = time_x(tick.to_utc());
+                        ui.painter().line_segment(
+                            [Pos2 { x, y: y_min }, Pos2 { x, y: y_max }],
+                            Stroke {
+                                width: 1.0,
+                                color: Color32::LIGHT_GRAY,
+                            },
+                        );
+                        ui.painter().text(
+                            Pos2 {
+                                x: x + 2.0,
+                                y: y_max - 1.0,
+                            },
+                            egui::Align2::LEFT_BOTTOM,
+                            tick.format("%H:%M"),
+                            egui::FontId::proportional(row_height / 9.0),
+                            Color32::GRAY,
+                        );
+                    }
+
+                    // one point every two pixels
+                    for point in
+                        room.sensor_history
+                            .downsample(now, HISTORY_LEN, width as usize / 2)
                     {
-                        if data.temperature < min_temp || data.temperature > max_temp {
+                        if point.temperature < min_temp || point.temperature > max_temp {
                             continue;
                         }
-                        let x = x_max
-                            - width / history_len.as_secs() as f32
-                                * (Instant::now() - *timestamp).as_secs() as f32;
+                        let x = time_x(point.timestamp);
                         let y =
-                            y_max - (data.temperature - min_temp) / (max_temp - min_temp) * height;
+                            y_max - (point.temperature - min_temp) / (max_temp - min_temp) * height;
                         ui.painter()
                             .circle_filled(Pos2 { x, y }, 1.0, Color32::BLUE);
                     }
-                }
-                if let Some(actor) = &mut room.actor {
-                    let buttons_pos = row_width - 3.5 * row_height;
-                    ui.put(
-                        Rect::from_two_pos(
-                            Pos2 {
-                                x: buttons_pos,
-                                y: pos + margin / 2.0,
-                            },
-                            Pos2 {
-                                x: buttons_pos + row_height - margin,
-                                y: pos + (row_height - margin) / 2.0,
-                            },
-                        ),
-                        Button::new("Auto"),
-                    );
-                    ui.put(
-                        Rect::from_two_pos(
-                            Pos2 {
-                                x: buttons_pos + row_height * 2.5,
-                                y: pos + margin / 2.0,
-                            },
-                            Pos2 {
-                                x: buttons_pos + row_height * 3.0 - margin,
-                                y: pos + (row_height - margin) / 2.0,
-                            },
-                        ),
-                        Button::new("⬆"),
+                } else if let Some(state) = room.connection {
+                    let text = match state {
+                        ConnectionState::Connecting => "connecting…",
+                        ConnectionState::Connected => "waiting for data…",
+                        ConnectionState::Disconnected => "disconnected",
+                    };
+                    ui.painter().text(
+                        egui::pos2(2.0 * margin, pos + row_height / 2.0 + 2.0 * margin),
+                        egui::Align2::LEFT_TOP,
+                        text,
+                        egui::FontId::proportional(row_height / 5.0),
+                        Color32::DARK_GRAY,
                     );
-                    ui.put(
-                        Rect::from_two_pos(
-                            Pos2 {
-                                x: buttons_pos + row_height * 3.0,
-                                y: pos + margin / 2.0,
-                            },
-                            Pos2 {
-                                x: buttons_pos + row_height * 3.5 - margin,
-                                y: pos + (row_height - margin) / 2.0,
-                            },
+                }
+                if let Some(actor) = &room.actor {
+                    if ui
+                        .put(
+                            Rect::from_min_size(
+                                egui::pos2(
+                                    2.0 * margin,
+                                    pos + row_height - margin - row_height / 6.0,
+                                ),
+                                egui::vec2(row_height / 5.0, row_height / 6.0),
+                            ),
+                            Button::new("📅"),
+                        )
+                        .clicked()
+                    {
+                        self.schedule_editor = Some(room.name.clone());
+                    }
+                    if let Some(text) = schedule_text(actor, &self.presets) {
+                        ui.painter().text(
+                            egui::pos2(2.0 * margin + row_height / 4.0, pos + row_height - margin),
+                            egui::Align2::LEFT_BOTTOM,
+                            text,
+                            egui::FontId::proportional(row_height / 8.0),
+                            Color32::BLACK,
+                        );
+                    }
+                    // an error is only shown until the relay is confirmed again
+                    let error = actor.status.last_error.as_ref().filter(|(_, at)| {
+                        actor
+                            .status
+                            .confirmed
+                            .is_none_or(|(_, confirmed)| confirmed <= *at)
+                    });
+                    let (text, color) = match (error, actor.status.confirmed) {
+                        _ if let Some(interlock) = actor.safety.active => {
+                            (format!("⚠ safety: {}", interlock.describe()), Color32::RED)
+                        }
+                        (Some((error, _)), _) => (format!("⚠ {error}"), Color32::RED),
+                        (None, Some((on, _))) => (
+                            format!("relay {}", if on { "on" } else { "off" }),
+                            Color32::BLACK,
                         ),
-                        Button::new("⬇"),
+                        (None, None) => ("relay unknown".to_string(), Color32::DARK_GRAY),
+                    };
+                    ui.painter().text(
+                        egui::pos2(row_width / 3.0 - margin, pos + row_height - margin),
+                        egui::Align2::RIGHT_BOTTOM,
+                        text,
+                        egui::FontId::proportional(row_height / 8.0),
+                        color,
                     );
+                }
+                if let Some(actor) = &mut room.actor {
+                    let buttons_pos = row_width - 3.5 * row_height;
+                    let auto_btn = if let HeatingState::Auto(target) = actor.state {
+                        Button::new(format!("Auto {target:.1}°C")).selected(true)
+                    } else {
+                        Button::new("Auto")
+                    };
+                    if ui
+                        .put(
+                            Rect::from_two_pos(
+                                Pos2 {
+                                    x: buttons_pos,
+                                    y: pos + margin / 2.0,
+                                },
+                                Pos2 {
+                                    x: buttons_pos + row_height * 2.5 - margin,
+                                    y: pos + (row_height - margin) / 2.0,
+                                },
+                            ),
+                            auto_btn,
+                        )
+                        .clicked()
+                        && !matches!(actor.state, HeatingState::Auto(_))
+                    {
+                        actor.state = HeatingState::Auto(DEFAULT_TARGET);
+                    }
+                    if ui
+                        .put(
+                            Rect::from_two_pos(
+                                Pos2 {
+                                    x: buttons_pos + row_height * 2.5,
+                                    y: pos + margin / 2.0,
+                                },
+                                Pos2 {
+                                    x: buttons_pos + row_height * 3.0 - margin,
+                                    y: pos + (row_height - margin) / 2.0,
+                                },
+                            ),
+                            Button::new("⬆"),
+                        )
+                        .clicked()
+                        && let HeatingState::Auto(target) = &mut actor.state
+                    {
+                        *target = (*target + TARGET_STEP).min(MAX_TARGET);
+                    }
+                    if ui
+                        .put(
+                            Rect::from_two_pos(
+                                Pos2 {
+                                    x: buttons_pos + row_height * 3.0,
+                                    y: pos + margin / 2.0,
+                                },
+                                Pos2 {
+                                    x: buttons_pos + row_height * 3.5 - margin,
+                                    y: pos + (row_height - margin) / 2.0,
+                                },
+                            ),
+                            Button::new("⬇"),
+                        )
+                        .clicked()
+                        && let HeatingState::Auto(target) = &mut actor.state
+                    {
+                        *target = (*target - TARGET_STEP).max(MIN_TARGET);
+                    }
                     for i in 0..=6 {
                         let btn = if let HeatingState::Manual(level) = &actor.state {
                             if *level == i {
@@ -245,22 +515,25 @@ impl eframe::App for MyApp {
                         } else {
                             Button::new(format!("{}", i))
                         };
-                        if ui.put(
-                            Rect::from_two_pos(
-                                Pos2 {
-                                    x: (buttons_pos as i32 + i as i32 * row_height as i32 / 2)
-                                        as f32,
-                                    y: pos + (row_height + margin) / 2.0,
-                                },
-                                Pos2 {
-                                    x: ((buttons_pos + row_height / 2.0 - margin) as i32
-                                        + i as i32 * row_height as i32 / 2)
-                                        as f32,
-                                    y: pos + row_height - margin / 2.0,
-                                },
-                            ),
-                            btn,
-                        ).clicked() {
+                        if ui
+                            .put(
+                                Rect::from_two_pos(
+                                    Pos2 {
+                                        x: (buttons_pos as i32 + i as i32 * row_height as i32 / 2)
+                                            as f32,
+                                        y: pos + (row_height + margin) / 2.0,
+                                    },
+                                    Pos2 {
+                                        x: ((buttons_pos + row_height / 2.0 - margin) as i32
+                                            + i as i32 * row_height as i32 / 2)
+                                            as f32,
+                                        y: pos + row_height - margin / 2.0,
+                                    },
+                                ),
+                                btn,
+                            )
+                            .clicked()
+                        {
                             actor.state = HeatingState::Manual(i);
                         };
                     }
@@ -268,15 +541,36 @@ impl eframe::App for MyApp {
                 pos += row_height;
             }
         });
+
+        if let Some(name) = self.schedule_editor.clone() {
+            let mut open = true;
+            if let Some(actor) = rooms
+                .iter_mut()
+                .find(|r| r.name == name)
+                .and_then(|r| r.actor.as_mut())
+            {
+                egui::Window::new(format!("Schedule {name}"))
+                    .open(&mut open)
+                    .show(ctx, |ui| {
+                        if schedule_editor(ui, &mut actor.schedule, &self.presets) {
+                            actor.schedule_edited = true;
+                        }
+                    });
+            } else {
+                open = false;
+            }
+            if !open {
+                self.schedule_editor = None;
+            }
+        }
     }
 
     fn save(&mut self, _storage: &mut dyn eframe::Storage) {
-        save_rooms_to_file(&*self.rooms.lock().unwrap(), "rooms.json");
-        println!("State saved.");
+        self.daemon.save();
     }
 
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
-        self.ct.cancel();
+        self.daemon.stop();
         println!("Exiting application.");
     }
 }